@@ -0,0 +1,369 @@
+use std::path::{ Path, PathBuf };
+
+use async_trait::async_trait;
+use riven::consts::{ Division, Queue, QueueType, Region, Tier };
+use riven::models::{ league_exp_v4, match_v4, summoner_v4 };
+use serde::{ Serialize, Deserialize };
+use serde::de::DeserializeOwned;
+use tokio::fs;
+
+use crate::dyn_err;
+use crate::util::error::PbwError;
+use super::{ Api, ApiResult };
+
+// Serves responses from a directory of recorded JSON files, for running offline.
+// Layout:
+// - `league/<region>/<queue_type>/<tier>/<division>/<page>.json`
+// - `summoner/<region>/<encrypted_summoner_id>.json`
+// - `matchlist/<region>/<queue>/<encrypted_account_id>.json`
+// - `match/<region>/<match_id>.json`
+// A missing file acts like an empty/404 response.
+pub struct FixtureApi {
+    dir: PathBuf,
+}
+
+impl FixtureApi {
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_owned(),
+        }
+    }
+
+    pub fn league_path(dir: &Path, region: Region, queue_type: QueueType,
+        tier: Tier, division: Division, page: i32) -> PathBuf
+    {
+        dir.join("league")
+            .join(format!("{:?}", region))
+            .join(format!("{:?}", queue_type))
+            .join(format!("{:?}", tier))
+            .join(format!("{:?}", division))
+            .join(format!("{}.json", page))
+    }
+
+    pub fn summoner_path(dir: &Path, region: Region, encrypted_summoner_id: &str) -> PathBuf {
+        dir.join("summoner")
+            .join(format!("{:?}", region))
+            .join(format!("{}.json", encrypted_summoner_id))
+    }
+
+    pub fn matchlist_path(dir: &Path, region: Region, encrypted_account_id: &str, queue: Queue) -> PathBuf {
+        dir.join("matchlist")
+            .join(format!("{:?}", region))
+            .join(format!("{:?}", queue))
+            .join(format!("{}.json", encrypted_account_id))
+    }
+
+    pub fn match_path(dir: &Path, region: Region, match_id: i64) -> PathBuf {
+        dir.join("match")
+            .join(format!("{:?}", region))
+            .join(format!("{}.json", match_id))
+    }
+
+    async fn read<T: DeserializeOwned>(path: PathBuf) -> ApiResult<Option<T>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let bytes = fs::read(path).await.map_err(dyn_err)?;
+        let value = serde_json::from_slice(&bytes).map_err(dyn_err)?;
+        Ok(Some(value))
+    }
+}
+
+// Start time of a recorded run (`run.json`), so a replay uses the same time windows.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RunInfo {
+    pub now_millis: i64,
+}
+
+impl RunInfo {
+    fn path(dir: &Path) -> PathBuf {
+        dir.join("run.json")
+    }
+
+    pub fn read(dir: impl AsRef<Path>) -> std::io::Result<Option<Self>> {
+        let path = Self::path(dir.as_ref());
+        if !path.exists() {
+            return Ok(None);
+        }
+        let bytes = std::fs::read(path)?;
+        let run_info = serde_json::from_slice(&bytes)?;
+        Ok(Some(run_info))
+    }
+
+    pub fn write(&self, dir: impl AsRef<Path>) -> std::io::Result<()> {
+        std::fs::create_dir_all(dir.as_ref())?;
+        let bytes = serde_json::to_vec_pretty(self)?;
+        std::fs::write(Self::path(dir.as_ref()), bytes)
+    }
+}
+
+#[async_trait]
+impl Api for FixtureApi {
+    async fn get_league_entries(&self, region: Region, queue_type: QueueType,
+        tier: Tier, division: Division, page: i32)
+        -> ApiResult<Vec<league_exp_v4::LeagueEntry>>
+    {
+        let path = Self::league_path(&self.dir, region, queue_type, tier, division, page);
+        Ok(Self::read(path).await?.unwrap_or_else(Vec::new))
+    }
+
+    async fn get_summoner(&self, region: Region, encrypted_summoner_id: &str)
+        -> ApiResult<summoner_v4::Summoner>
+    {
+        let path = Self::summoner_path(&self.dir, region, encrypted_summoner_id);
+        Self::read(path).await?
+            .ok_or_else(|| dyn_err(PbwError::new(
+                format!("No summoner fixture: {}.", encrypted_summoner_id))))
+    }
+
+    async fn get_matchlist(&self, region: Region, encrypted_account_id: &str,
+        begin_time: i64, queue: Queue)
+        -> ApiResult<Option<match_v4::Matchlist>>
+    {
+        let path = Self::matchlist_path(&self.dir, region, encrypted_account_id, queue);
+        let matchlist: Option<match_v4::Matchlist> = Self::read(path).await?;
+        // Apply `begin_time` like the API does.
+        Ok(matchlist.map(|mut matchlist| {
+            matchlist.matches.retain(|matche| matche.timestamp >= begin_time);
+            matchlist
+        }))
+    }
+
+    async fn get_match(&self, region: Region, match_id: i64)
+        -> ApiResult<Option<match_v4::Match>>
+    {
+        let path = Self::match_path(&self.dir, region, match_id);
+        Self::read(path).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use chrono::{ Duration, Utc };
+    use serde_json::{ json, Value };
+
+    use crate::config::Config;
+    use crate::pipeline::source_fs;
+    use crate::util::file_find;
+    use crate::util::queue;
+
+    fn write_json(path: PathBuf, value: Value) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, serde_json::to_vec(&value).unwrap()).unwrap();
+    }
+
+    fn league_entry(summoner_id: &str, league_points: i32) -> Value {
+        json!({
+            "leagueId": "league-gold", "summonerId": summoner_id, "summonerName": summoner_id,
+            "queueType": QueueType::RANKED_SOLO_5x5, "tier": Tier::GOLD, "rank": Division::I,
+            "leaguePoints": league_points, "wins": 10, "losses": 10,
+            "hotStreak": false, "veteran": false, "freshBlood": false, "inactive": false,
+        })
+    }
+
+    fn summoner(summoner_id: &str, account_id: &str) -> Value {
+        json!({
+            "id": summoner_id, "accountId": account_id, "puuid": summoner_id, "name": summoner_id,
+            "profileIconId": 1, "revisionDate": 0, "summonerLevel": 100,
+        })
+    }
+
+    fn player(summoner_id: &str, account_id: &str, platform_id: &str) -> Value {
+        json!({
+            "summonerId": summoner_id, "accountId": account_id, "currentAccountId": account_id,
+            "platformId": platform_id, "currentPlatformId": platform_id,
+            "summonerName": summoner_id, "matchHistoryUri": "", "profileIcon": 1,
+        })
+    }
+
+    // Every `ParticipantStats` field, zeroed.
+    fn participant_stats(participant_id: i32, win: bool) -> Value {
+        let mut keys: Vec<String> = vec![
+            "goldEarned", "goldSpent", "totalScoreRank", "champLevel", "kills", "deaths", "assists",
+            "doubleKills", "tripleKills", "quadraKills", "pentaKills", "unrealKills",
+            "largestKillingSpree", "largestMultiKill", "killingSprees", "longestTimeSpentLiving",
+            "largestCriticalStrike", "totalDamageDealt", "totalDamageDealtToChampions", "totalDamageTaken",
+            "magicDamageDealt", "magicDamageDealtToChampions", "magicalDamageTaken",
+            "physicalDamageDealt", "physicalDamageDealtToChampions", "physicalDamageTaken",
+            "trueDamageDealt", "trueDamageDealtToChampions", "trueDamageTaken",
+            "totalHeal", "totalUnitsHealed", "damageSelfMitigated", "damageDealtToObjectives",
+            "damageDealtToTurrets", "visionScore", "timeCCingOthers", "totalTimeCrowdControlDealt",
+            "totalMinionsKilled", "neutralMinionsKilled", "neutralMinionsKilledTeamJungle",
+            "neutralMinionsKilledEnemyJungle", "visionWardsBoughtInGame", "sightWardsBoughtInGame",
+            "wardsPlaced", "wardsKilled", "turretKills", "inhibitorKills",
+            "combatPlayerScore", "objectivePlayerScore", "totalPlayerScore",
+            "altarsCaptured", "altarsNeutralized", "nodeCapture", "nodeCaptureAssist",
+            "nodeNeutralize", "nodeNeutralizeAssist", "teamObjective", "perkPrimaryStyle", "perkSubStyle",
+        ].into_iter().map(str::to_owned).collect();
+        keys.extend((0..7).map(|i| format!("item{}", i)));
+        keys.extend((0..10).map(|i| format!("playerScore{}", i)));
+        keys.extend((0..3).map(|i| format!("statPerk{}", i)));
+        for i in 0..6 {
+            keys.push(format!("perk{}", i));
+            keys.extend((1..4).map(|var| format!("perk{}Var{}", i, var)));
+        }
+
+        let mut stats = serde_json::Map::new();
+        for key in keys {
+            stats.insert(key, json!(0));
+        }
+        for key in &[ "firstBloodKill", "firstBloodAssist", "firstTowerKill", "firstTowerAssist",
+            "firstInhibitorKill", "firstInhibitorAssist" ]
+        {
+            stats.insert(key.to_string(), json!(false));
+        }
+        stats.insert("participantId".to_owned(), json!(participant_id));
+        stats.insert("win".to_owned(), json!(win));
+        Value::Object(stats)
+    }
+
+    fn participant(participant_id: i32, champion_id: i32, team_id: i32, lane: &str) -> Value {
+        json!({
+            "participantId": participant_id, "championId": champion_id, "teamId": team_id,
+            "spell1Id": 4, "spell2Id": 14, "highestAchievedSeasonTier": "GOLD",
+            "runes": [], "masteries": [],
+            "stats": participant_stats(participant_id, 100 == team_id),
+            "timeline": {
+                "participantId": participant_id, "role": "SOLO", "lane": lane,
+                "creepsPerMinDeltas": {}, "xpPerMinDeltas": {}, "goldPerMinDeltas": {},
+                "csDiffPerMinDeltas": {}, "xpDiffPerMinDeltas": {},
+                "damageTakenPerMinDeltas": {}, "damageTakenDiffPerMinDeltas": {},
+            },
+        })
+    }
+
+    fn team(team_id: i32, win: bool, bans: Value) -> Value {
+        json!({
+            "teamId": team_id, "win": if win { "Win" } else { "Fail" }, "bans": bans,
+            "firstBlood": win, "firstTower": win, "firstInhibitor": win, "firstBaron": false,
+            "firstDragon": false, "firstRiftHerald": false,
+            "towerKills": 0, "inhibitorKills": 0, "baronKills": 0, "dragonKills": 0,
+            "vilemawKills": 0, "riftHeraldKills": 0, "dominionVictoryScore": 0,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_missing() {
+        let api = FixtureApi::new(std::env::temp_dir().join("pbw_fixture_missing"));
+
+        let entries = api.get_league_entries(Region::NA, QueueType::RANKED_SOLO_5x5,
+            Tier::GOLD, Division::I, 1).await.unwrap();
+        assert!(entries.is_empty());
+
+        assert!(api.get_match(Region::NA, 3300000000).await.unwrap().is_none());
+        assert!(api.get_summoner(Region::NA, "abc").await.is_err());
+    }
+
+    // Runs NA through `crate::run_async` twice, as from scratch: the first run pulls ranks into a new
+    // summoner file, the second scans the summoners' matchlists and fetches their match.
+    #[tokio::test(threaded_scheduler)]
+    async fn test_run_async() {
+        let dir = std::env::temp_dir().join("pbw_fixture_run");
+        let _ = std::fs::remove_dir_all(&dir);
+        let fixture_dir = dir.join("fixture");
+        let mut config = Config::default();
+        config.data_root = dir.join("data");
+        let queue = queue::SOLO;
+        let region = Region::NA;
+        let platform_id = region.to_string();
+
+        let now = Utc::now();
+        let match_id: i64 = 3300000001;
+        let game_creation = (now - Duration::days(1)).timestamp_millis();
+
+        write_json(FixtureApi::league_path(&fixture_dir, region, queue.league_queue_type, Tier::GOLD, Division::I, 1),
+            json!([ league_entry("sid-a", 50), league_entry("sid-b", 70) ]));
+        for (summoner_id, account_id) in &[ ("sid-a", "aid-a"), ("sid-b", "aid-b") ] {
+            write_json(FixtureApi::summoner_path(&fixture_dir, region, summoner_id),
+                summoner(summoner_id, account_id));
+            write_json(FixtureApi::matchlist_path(&fixture_dir, region, account_id, queue.queue), json!({
+                "startIndex": 0, "endIndex": 1, "totalGames": 1,
+                "matches": [{
+                    "gameId": match_id, "timestamp": game_creation, "platformId": platform_id,
+                    "champion": 266, "queue": 420, "season": 13, "role": "SOLO", "lane": "TOP",
+                }],
+            }));
+        }
+        write_json(FixtureApi::match_path(&fixture_dir, region, match_id), json!({
+            "gameId": match_id, "platformId": platform_id, "gameCreation": game_creation,
+            "gameDuration": 1834, "gameVersion": "10.4.311.9134", "queueId": 420, "seasonId": 13,
+            "mapId": 11, "gameMode": "CLASSIC", "gameType": "MATCHED_GAME",
+            "participantIdentities": [
+                { "participantId": 1, "player": player("sid-a", "aid-a", &platform_id) },
+                { "participantId": 2, "player": player("sid-b", "aid-b", &platform_id) },
+                { "participantId": 3, "player": player("sid-c", "aid-c", &platform_id) },
+                // Transferred away, its IDs belong to another region.
+                { "participantId": 4, "player": player("sid-d", "aid-d", "EUW1") },
+            ],
+            "participants": [
+                participant(1, 266, 100, "TOP"),
+                participant(2, 412, 200, "TOP"),
+                participant(3, 22, 100, "BOTTOM"),
+                participant(4, 51, 200, "BOTTOM"),
+            ],
+            "teams": [
+                team(100, true, json!([ { "championId": 350, "pickTurn": 1 } ])),
+                team(200, false, json!([ { "championId": -1, "pickTurn": 2 } ])),
+            ],
+        }));
+
+        let api: &'static FixtureApi = Box::leak(Box::new(FixtureApi::new(&fixture_dir)));
+
+        let summary = crate::run_async(api, &config, now, queue, region, 10, true).await.unwrap();
+        assert_eq!(0, summary.summoners_updated);
+        assert_eq!(0, summary.matches_fetched);
+
+        let summary = crate::run_async(api, &config, now, queue, region, 10, false).await.unwrap();
+        assert_eq!(2, summary.summoners_updated);
+        assert_eq!(1, summary.new_match_ids);
+        assert_eq!(1, summary.matches_fetched);
+        assert_eq!(1, summary.match_files_written);
+
+        let path_data = queue.path_data(&config.data_root, region);
+        assert!(crate::pipeline::checkpoint::read_checkpoint(path_data.join("local")).unwrap().is_none());
+
+        // Scanned summoners are updated, the participant on this platform is added.
+        let summoners: HashMap<String, crate::model::summoner::Summoner> =
+            source_fs::get_all_summoners(path_data.join("local")).unwrap().unwrap()
+                .map(|summoner| (summoner.encrypted_summoner_id.clone(), summoner))
+                .collect();
+        assert_eq!(3, summoners.len());
+        let summoner_a = &summoners["sid-a"];
+        assert_eq!(Some("aid-a"), summoner_a.encrypted_account_id.as_deref());
+        assert_eq!(Some(now.timestamp_millis() as u64), summoner_a.ts);
+        assert_eq!(Some(Tier::GOLD), summoner_a.rank_tier);
+        assert!(summoner_a.games_per_day.is_some());
+        assert_eq!(Some("aid-c"), summoners["sid-c"].encrypted_account_id.as_deref());
+        assert_eq!(None, summoners["sid-c"].rank_tier);
+        assert!(!summoners.contains_key("sid-d"));
+
+        // Match, ranked by the two known participants.
+        let path_patch = path_data.join("10.4");
+        let match_paths = file_find::find_all(&path_patch, "matches", "csv.gz").unwrap();
+        assert_eq!(1, match_paths.len());
+        let matches = source_fs::get_matches(&match_paths[0]).unwrap().collect::<std::io::Result<Vec<_>>>().unwrap();
+        assert_eq!(1, matches.len());
+        let matche = &matches[0];
+        assert_eq!(match_id as u64, matche.match_id);
+        assert_eq!(Some(Tier::GOLD), matche.rank_tier);
+        assert_eq!(Some(Division::I), matche.rank_division);
+        assert_eq!(Some(100), matche.winner);
+        assert_eq!(4, matche.picks.len());
+        assert_eq!(2, matche.bans.len());
+
+        // Stats of the same week.
+        let iso_week_str = file_find::get_infix(&match_paths[0], "matches", "csv.gz").unwrap();
+        let stats = source_fs::get_stats(&path_patch, iso_week_str).unwrap().unwrap()
+            .map(|row| (row.champion_id, row))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(5, stats.len());
+        assert_eq!((1, 1, 0, 1), (stats[&266].games, stats[&266].picks, stats[&266].bans, stats[&266].wins));
+        assert_eq!((1, 0), (stats[&412].picks, stats[&412].wins));
+        assert_eq!((0, 1), (stats[&350].picks, stats[&350].bans));
+        assert!(stats.values().all(|row| Some(Tier::GOLD) == row.rank_tier));
+    }
+}
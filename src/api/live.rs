@@ -0,0 +1,50 @@
+use async_trait::async_trait;
+use riven::RiotApi;
+use riven::consts::{ Division, Queue, QueueType, Region, Tier };
+use riven::models::{ league_exp_v4, match_v4, summoner_v4 };
+
+use crate::dyn_err;
+use super::{ Api, ApiResult };
+
+#[async_trait]
+impl Api for RiotApi {
+    async fn get_league_entries(&self, region: Region, queue_type: QueueType,
+        tier: Tier, division: Division, page: i32)
+        -> ApiResult<Vec<league_exp_v4::LeagueEntry>>
+    {
+        self.league_exp_v4().get_league_entries(region, queue_type, tier, division, Some(page)).await
+            .map_err(dyn_err)
+    }
+
+    async fn get_summoner(&self, region: Region, encrypted_summoner_id: &str)
+        -> ApiResult<summoner_v4::Summoner>
+    {
+        self.summoner_v4().get_by_summoner_id(region, encrypted_summoner_id).await
+            .map_err(dyn_err)
+    }
+
+    async fn get_matchlist(&self, region: Region, encrypted_account_id: &str,
+        begin_time: i64, queue: Queue)
+        -> ApiResult<Option<match_v4::Matchlist>>
+    {
+        self.match_v4().get_matchlist(
+            region,
+            encrypted_account_id,
+            Some(begin_time), // begin_time
+            None, // begin_index
+            None, // champion
+            None, // end_time
+            None, // end_index
+            Some(vec![ queue ]), // queue
+            None, // season
+        ).await
+            .map_err(dyn_err)
+    }
+
+    async fn get_match(&self, region: Region, match_id: i64)
+        -> ApiResult<Option<match_v4::Match>>
+    {
+        self.match_v4().get_match(region, match_id).await
+            .map_err(dyn_err)
+    }
+}
@@ -0,0 +1,34 @@
+use std::error::Error;
+
+use async_trait::async_trait;
+use riven::consts::{ Division, Queue, QueueType, Region, Tier };
+use riven::models::{ league_exp_v4, match_v4, summoner_v4 };
+
+mod fixture;
+mod live;
+mod record;
+
+pub use fixture::{ FixtureApi, RunInfo };
+pub use record::RecordingApi;
+
+pub type ApiResult<T> = Result<T, Box<dyn Error + Send>>;
+
+// The Riot API endpoints used by the pipeline.
+// Implemented by `RiotApi` (live), `FixtureApi` (recorded JSON files),
+// and `RecordingApi` (records another implementation's responses).
+#[async_trait]
+pub trait Api: Send + Sync {
+    async fn get_league_entries(&self, region: Region, queue_type: QueueType,
+        tier: Tier, division: Division, page: i32)
+        -> ApiResult<Vec<league_exp_v4::LeagueEntry>>;
+
+    async fn get_summoner(&self, region: Region, encrypted_summoner_id: &str)
+        -> ApiResult<summoner_v4::Summoner>;
+
+    async fn get_matchlist(&self, region: Region, encrypted_account_id: &str,
+        begin_time: i64, queue: Queue)
+        -> ApiResult<Option<match_v4::Matchlist>>;
+
+    async fn get_match(&self, region: Region, match_id: i64)
+        -> ApiResult<Option<match_v4::Match>>;
+}
@@ -0,0 +1,81 @@
+use std::path::{ Path, PathBuf };
+
+use async_trait::async_trait;
+use riven::consts::{ Division, Queue, QueueType, Region, Tier };
+use riven::models::{ league_exp_v4, match_v4, summoner_v4 };
+use serde::Serialize;
+use tokio::fs;
+
+use crate::dyn_err;
+use super::{ Api, ApiResult, FixtureApi };
+
+// Tees every successful response of `inner` to `dir`, in the `FixtureApi` layout,
+// so the run can be replayed later.
+pub struct RecordingApi {
+    inner: &'static dyn Api,
+    dir: PathBuf,
+}
+
+impl RecordingApi {
+    pub fn new(inner: &'static dyn Api, dir: impl AsRef<Path>) -> Self {
+        Self {
+            inner: inner,
+            dir: dir.as_ref().to_owned(),
+        }
+    }
+
+    async fn write<T: Serialize>(path: PathBuf, value: &T) -> ApiResult<()> {
+        let bytes = serde_json::to_vec(value).map_err(dyn_err)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await.map_err(dyn_err)?;
+        }
+        fs::write(path, bytes).await.map_err(dyn_err)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Api for RecordingApi {
+    async fn get_league_entries(&self, region: Region, queue_type: QueueType,
+        tier: Tier, division: Division, page: i32)
+        -> ApiResult<Vec<league_exp_v4::LeagueEntry>>
+    {
+        let entries = self.inner.get_league_entries(region, queue_type, tier, division, page).await?;
+        let path = FixtureApi::league_path(&self.dir, region, queue_type, tier, division, page);
+        Self::write(path, &entries).await?;
+        Ok(entries)
+    }
+
+    async fn get_summoner(&self, region: Region, encrypted_summoner_id: &str)
+        -> ApiResult<summoner_v4::Summoner>
+    {
+        let summoner = self.inner.get_summoner(region, encrypted_summoner_id).await?;
+        let path = FixtureApi::summoner_path(&self.dir, region, encrypted_summoner_id);
+        Self::write(path, &summoner).await?;
+        Ok(summoner)
+    }
+
+    async fn get_matchlist(&self, region: Region, encrypted_account_id: &str,
+        begin_time: i64, queue: Queue)
+        -> ApiResult<Option<match_v4::Matchlist>>
+    {
+        let matchlist = self.inner.get_matchlist(region, encrypted_account_id, begin_time, queue).await?;
+        // 404s are left missing, which `FixtureApi` treats as 404.
+        if let Some(matchlist) = &matchlist {
+            let path = FixtureApi::matchlist_path(&self.dir, region, encrypted_account_id, queue);
+            Self::write(path, matchlist).await?;
+        }
+        Ok(matchlist)
+    }
+
+    async fn get_match(&self, region: Region, match_id: i64)
+        -> ApiResult<Option<match_v4::Match>>
+    {
+        let matche = self.inner.get_match(region, match_id).await?;
+        if let Some(matche) = &matche {
+            let path = FixtureApi::match_path(&self.dir, region, match_id);
+            Self::write(path, matche).await?;
+        }
+        Ok(matche)
+    }
+}
@@ -0,0 +1,296 @@
+use std::path::{ Path, PathBuf };
+
+use chrono::Duration;
+use riven::RiotApiConfig;
+use serde::Deserialize;
+
+use crate::pipeline::compact::Retention;
+use crate::pipeline::selection::{ Selection, TierWeights };
+use crate::util::error::PbwError;
+use crate::util::queue::{ self, QueueConfig };
+
+// Config file used if `--config`/`PBW_CONFIG` are not given (and it exists).
+const DEFAULT_CONFIG_PATH: &'static str = "pickbanwin.toml";
+const ENV_PREFIX: &'static str = "PBW_";
+
+// Keys that can be set in the TOML file, as `PBW_<KEY>` env vars, or as `--<flag>` CLI args.
+// Later sources override earlier ones.
+pub const KEYS: [(&'static str, &'static str); 17] = [
+    ("api_key",                "api-key"),
+    ("preconfig",              "preconfig"),
+    ("retries",                "retries"),
+    ("burst_pct",              "burst-pct"),
+    ("duration_overhead_ms",   "duration-overhead-ms"),
+    ("lookbehind_days",        "lookbehind-days"),
+    ("account_id_batch_size",  "account-id-batch-size"),
+    ("matchlist_batch_size",   "matchlist-batch-size"),
+    ("match_batch_size",       "match-batch-size"),
+    ("league_page_batch_size", "league-page-batch-size"),
+    ("channel_capacity",       "channel-capacity"),
+    ("keep_last_snapshots",    "keep-last-snapshots"),
+    ("keep_daily_days",        "keep-daily-days"),
+    ("data_root",              "data-root"),
+    ("queue",                  "queue"),
+    ("selection",              "selection"),
+    ("tier_weights",           "tier-weights"),
+];
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub api_key: Option<String>,
+    // "throughput" or "burst".
+    pub preconfig: String,
+    pub retries: Option<u8>,
+    pub burst_pct: Option<f32>,
+    pub duration_overhead_ms: Option<u64>,
+
+    pub lookbehind_days: i64,
+    pub account_id_batch_size: usize,
+    pub matchlist_batch_size: usize,
+    pub match_batch_size: usize,
+    pub league_page_batch_size: usize,
+    // Capacity of each channel between match fetching and the consumer stages.
+    pub channel_capacity: usize,
+    // Retention policy for `compact`.
+    pub keep_last_snapshots: usize,
+    pub keep_daily_days: i64,
+
+    pub data_root: PathBuf,
+    // Name of a `util::queue::QueueConfig`.
+    pub queue: String,
+    // Summoner selection strategy, see `pipeline::selection::Selection`.
+    pub selection: String,
+    // Per-tier weights for stratified selection, see `pipeline::selection::TierWeights`.
+    pub tier_weights: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            api_key: None,
+            preconfig: "throughput".to_owned(),
+            retries: None,
+            burst_pct: None,
+            duration_overhead_ms: None,
+
+            lookbehind_days: 7,
+            account_id_batch_size: 20,
+            matchlist_batch_size: 20,
+            match_batch_size: 40,
+            league_page_batch_size: 10,
+            channel_capacity: 256,
+            keep_last_snapshots: 5,
+            keep_daily_days: 30,
+
+            data_root: PathBuf::from("data"),
+            queue: queue::SOLO.name.to_owned(),
+            selection: "oldest".to_owned(),
+            tier_weights: String::new(),
+        }
+    }
+}
+
+fn positive<T: PartialOrd + Default + std::fmt::Display>(key: &str, val: T) -> Result<T, PbwError> {
+    if val > T::default() {
+        Ok(val)
+    } else {
+        Err(PbwError::new(format!("Invalid value for {}: {}, must be positive.", key, val)))
+    }
+}
+
+impl Config {
+    // Loads the TOML file (if any), then applies env vars, then `cli_overrides`.
+    pub fn load<'a>(path: Option<&Path>, cli_overrides: impl Iterator<Item = (&'a str, &'a str)>)
+        -> Result<Self, PbwError>
+    {
+        let env_path = std::env::var_os(format!("{}CONFIG", ENV_PREFIX)).map(PathBuf::from);
+        let path = path.map(Path::to_owned).or(env_path);
+
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None => {
+                let path = Path::new(DEFAULT_CONFIG_PATH);
+                if path.exists() { Self::from_file(path)? } else { Self::default() }
+            },
+        };
+
+        for (key, _flag) in KEYS.iter() {
+            if let Ok(val) = std::env::var(format!("{}{}", ENV_PREFIX, key.to_uppercase())) {
+                config.set(key, &val)?;
+            }
+        }
+        for (key, val) in cli_overrides {
+            config.set(key, val)?;
+        }
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, PbwError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| PbwError::new(format!("Failed to read config {:?}: {}", path, e)))?;
+        let config: Self = toml::from_str(&text)
+            .map_err(|e| PbwError::new(format!("Failed to parse config {:?}: {}", path, e)))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Rejects values the crawler can't run with (a batch size of 0 never advances, a channel of 0 panics).
+    pub fn validate(&self) -> Result<(), PbwError> {
+        positive("lookbehind_days", self.lookbehind_days)?;
+        positive("account_id_batch_size", self.account_id_batch_size)?;
+        positive("matchlist_batch_size", self.matchlist_batch_size)?;
+        positive("match_batch_size", self.match_batch_size)?;
+        positive("league_page_batch_size", self.league_page_batch_size)?;
+        positive("channel_capacity", self.channel_capacity)?;
+        Ok(())
+    }
+
+    // Sets a single value by key, parsing it from a string.
+    pub fn set(&mut self, key: &str, val: &str) -> Result<(), PbwError> {
+        fn parse<T: std::str::FromStr>(key: &str, val: &str) -> Result<T, PbwError> {
+            val.parse().map_err(|_e| PbwError::new(format!("Invalid value for {}: {}.", key, val)))
+        }
+        match key {
+            "api_key" => self.api_key = Some(val.trim().to_owned()),
+            "preconfig" => self.preconfig = val.to_owned(),
+            "retries" => self.retries = Some(parse(key, val)?),
+            "burst_pct" => self.burst_pct = Some(parse(key, val)?),
+            "duration_overhead_ms" => self.duration_overhead_ms = Some(parse(key, val)?),
+            "lookbehind_days" => self.lookbehind_days = positive(key, parse(key, val)?)?,
+            "account_id_batch_size" => self.account_id_batch_size = positive(key, parse(key, val)?)?,
+            "matchlist_batch_size" => self.matchlist_batch_size = positive(key, parse(key, val)?)?,
+            "match_batch_size" => self.match_batch_size = positive(key, parse(key, val)?)?,
+            "league_page_batch_size" => self.league_page_batch_size = positive(key, parse(key, val)?)?,
+            "channel_capacity" => self.channel_capacity = positive(key, parse(key, val)?)?,
+            "keep_last_snapshots" => self.keep_last_snapshots = parse(key, val)?,
+            "keep_daily_days" => self.keep_daily_days = parse(key, val)?,
+            "data_root" => self.data_root = PathBuf::from(val),
+            "queue" => {
+                self.queue = val.to_owned();
+                self.queue()?;
+            },
+            "selection" => {
+                self.selection = val.to_owned();
+                self.selection()?;
+            },
+            "tier_weights" => {
+                self.tier_weights = val.to_owned();
+                self.tier_weights()?;
+            },
+            _ => return Err(PbwError::new(format!("Unknown config key: {}.", key))),
+        };
+        Ok(())
+    }
+
+    pub fn queue(&self) -> Result<QueueConfig, PbwError> {
+        queue::parse(&self.queue)
+            .ok_or_else(|| PbwError::new(format!("Unknown queue: {}, expected one of: {}.", self.queue,
+                queue::ALL.iter().map(|queue| queue.name).collect::<Vec<_>>().join(", "))))
+    }
+
+    pub fn selection(&self) -> Result<Selection, PbwError> {
+        Selection::parse(&self.selection)
+    }
+
+    pub fn tier_weights(&self) -> Result<TierWeights, PbwError> {
+        TierWeights::parse(&self.tier_weights)
+    }
+
+    pub fn lookbehind(&self) -> Duration {
+        Duration::days(self.lookbehind_days)
+    }
+
+    pub fn retention(&self) -> Retention {
+        Retention {
+            keep_last: self.keep_last_snapshots,
+            keep_daily_days: self.keep_daily_days,
+        }
+    }
+
+    pub fn riot_api_config(&self) -> Result<RiotApiConfig, PbwError> {
+        let api_key = self.api_key.as_ref()
+            .ok_or_else(|| PbwError::new(format!(
+                "Missing API key, set api_key in config, {}API_KEY, or --api-key.", ENV_PREFIX)))?;
+
+        let mut config = RiotApiConfig::with_key(api_key);
+        config = match &*self.preconfig {
+            "throughput" => config.preconfig_throughput(),
+            "burst" => config.preconfig_burst(),
+            other => return Err(PbwError::new(format!("Unknown preconfig: {}.", other))),
+        };
+        if let Some(retries) = self.retries {
+            config = config.set_retries(retries);
+        }
+        if let Some(burst_pct) = self.burst_pct {
+            config = config.set_burst_pct(burst_pct);
+        }
+        if let Some(duration_overhead_ms) = self.duration_overhead_ms {
+            config = config.set_duration_overhead(std::time::Duration::from_millis(duration_overhead_ms));
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config: Config = toml::from_str(r#"
+            api_key = "RGAPI-xyz"
+            match_batch_size = 80
+            data_root = "/tmp/pbw"
+        "#).unwrap();
+        assert_eq!(Some("RGAPI-xyz"), config.api_key.as_deref());
+        assert_eq!(80, config.match_batch_size);
+        assert_eq!(20, config.matchlist_batch_size);
+        assert_eq!(7, config.lookbehind_days);
+        assert_eq!(PathBuf::from("/tmp/pbw"), config.data_root);
+        config.validate().unwrap();
+
+        let config: Config = toml::from_str("matchlist_batch_size = 0").unwrap();
+        assert!(config.validate().is_err());
+        let config: Config = toml::from_str("lookbehind_days = -1").unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_set() {
+        let mut config = Config::default();
+        config.set("lookbehind_days", "3").unwrap();
+        config.set("retries", "5").unwrap();
+        assert_eq!(Duration::days(3), config.lookbehind());
+        assert_eq!(Some(5), config.retries);
+
+        assert!(config.set("lookbehind_days", "abc").is_err());
+        assert!(config.set("lookbehind_days", "0").is_err());
+        assert!(config.set("match_batch_size", "0").is_err());
+        assert!(config.set("league_page_batch_size", "0").is_err());
+        assert!(config.set("channel_capacity", "0").is_err());
+        assert_eq!(Duration::days(3), config.lookbehind());
+        assert_eq!(40, config.match_batch_size);
+        assert!(config.set("not_a_key", "1").is_err());
+
+        config.set("queue", "aram").unwrap();
+        assert_eq!(queue::ARAM, config.queue().unwrap());
+        assert!(config.set("queue", "blind").is_err());
+
+        assert_eq!(Selection::Oldest, config.selection().unwrap());
+        config.set("selection", "yield").unwrap();
+        assert_eq!(Selection::Yield, config.selection().unwrap());
+        assert!(config.set("selection", "random").is_err());
+    }
+
+    #[test]
+    fn test_keys_settable() {
+        let mut config = Config::default();
+        for (key, _flag) in KEYS.iter() {
+            // Every key is known (parse errors are fine).
+            if let Err(e) = config.set(key, "1") {
+                assert!(!e.to_string().starts_with("Unknown config key"), "{}", e);
+            }
+        }
+    }
+}
@@ -55,6 +55,8 @@
 
 #[macro_use] extern crate lazy_static;
 
+mod api;
+mod config;
 mod util;
 mod model;
 mod pipeline;
@@ -64,68 +66,123 @@ use std::error::Error;
 use std::path::{ PathBuf };
 use std::vec::Vec;
 use std::sync::Arc;
+use std::time::Instant;
 
-use chrono::{ Duration };
+use chrono::{ DateTime, TimeZone };
 use chrono::offset::Utc;
-use futures::future::join_all;
+use futures::future::{ join_all, FutureExt };
 // use itertools::Itertools;
-use riven::{ RiotApi, RiotApiConfig };
-use riven::consts::{ Region, Queue, QueueType, Tier };
-use riven::models::match_v4;
+use riven::RiotApi;
+use riven::consts::Region;
 use tokio::fs;
 use tokio::task;
-use tokio::sync::mpsc;
 
+use api::{ Api, FixtureApi, RecordingApi, RunInfo };
+use config::Config;
 use model::summoner::Summoner;
-use model::r#match::{ MatchFileKey, Match };
 use pipeline::basic;
+use pipeline::channel;
+use pipeline::checkpoint;
+use pipeline::compact::{ self, CompactSummary };
 use pipeline::source_fs;
 use pipeline::mapping_api;
-use util::time;
+use pipeline::match_sink::MatchSink;
+use pipeline::match_stages;
+use pipeline::rank_history::{ self, RankHistory };
+use pipeline::watermark;
+use util::error::PbwError;
 use util::hybitset::HyBitSet;
+use util::queue::QueueConfig;
+use util::shutdown;
 
 
-lazy_static! {
-    static ref RIOT_API: RiotApi =
-        RiotApi::with_config(
-            RiotApiConfig::with_key(include_str!("apikey.txt").trim())
-                .preconfig_throughput());
-}
-
 
 pub fn dyn_err<E: Error + Send + 'static>(e: E) -> Box<dyn Error + Send> {
     Box::new(e)
 }
 
-pub fn distance<T: std::ops::Sub<Output = T> + Ord>(x: T, y: T) -> T {
-    if x < y {
-        y - x
-    } else {
-        x - y
+
+
+
+// Runs all `regions` concurrently, then prints a combined summary.
+// Returns false if any region failed.
+async fn run_regions_async(api: &'static dyn Api, config: &Config, now: DateTime<Utc>,
+    regions: &[Region], update_size: usize, pull_ranks: bool)
+    -> bool
+{
+    let queue = match config.queue() {
+        Ok(queue) => queue,
+        Err(e) => {
+            println!("{}", e);
+            return false;
+        },
+    };
+    println!("Queue: {}.", queue.name);
+
+    let runs = regions.iter()
+        .map(|&region| run_async(api, config, now, queue, region, update_size, pull_ranks));
+    let results = join_all(runs).await;
+
+    println!("Summary:");
+    let mut ok = true;
+    let mut total = RunSummary::default();
+    for (region, result) in regions.iter().zip(results) {
+        match result {
+            Ok(summary) => {
+                println!("  {:>5}: {}", format!("{:?}", region), summary);
+                total.add(&summary);
+            },
+            Err(e) => {
+                println!("  {:>5}: FAILED: {}", format!("{:?}", region), e);
+                ok = false;
+            },
+        }
     }
+    println!("  {:>5}: {}", "TOTAL", total);
+    ok
 }
 
+#[derive(Default, Debug)]
+pub struct RunSummary {
+    pub summoners_updated: usize,
+    pub new_match_ids: usize,
+    pub matches_fetched: usize,
+    pub match_files_written: usize,
+}
 
-const QUEUE_TYPE: QueueType = QueueType::RANKED_SOLO_5x5;
-const QUEUE: Queue = Queue::SUMMONERS_RIFT_5V5_RANKED_SOLO_GAMES;
+impl RunSummary {
+    fn add(&mut self, other: &RunSummary) {
+        self.summoners_updated   += other.summoners_updated;
+        self.new_match_ids       += other.new_match_ids;
+        self.matches_fetched     += other.matches_fetched;
+        self.match_files_written += other.match_files_written;
+    }
+}
 
+impl std::fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} summoners updated, {} new match IDs, {} matches fetched, {} match files written.",
+            self.summoners_updated, self.new_match_ids, self.matches_fetched, self.match_files_written)
+    }
+}
 
+async fn run_async(api: &'static dyn Api, config: &Config, now: DateTime<Utc>,
+    queue: QueueConfig, region: Region, update_size: usize, pull_ranks: bool)
+    -> Result<RunSummary, Box<dyn Error>>
+{
+    let mut summary = RunSummary::default();
 
-async fn run_async(region: Region, update_size: usize, pull_ranks: bool) -> Result<(), Box<dyn Error>> {
-    println!("Updating {} in region {:?}", update_size, region);
+    println!("[{:?}] Updating {} summoners.", region, update_size);
     if pull_ranks {
-        println!("Updating ranks from API.");
+        println!("[{:?}] Updating ranks from API.", region);
     } else {
-        println!("Using stored ranks.");
+        println!("[{:?}] Using stored ranks.", region);
     }
 
-    let lookbehind = Duration::weeks(1);
-    let starttime = Utc::now() - lookbehind;
+    let lookbehind = config.lookbehind();
+    let starttime = now - lookbehind;
 
-    let path_data: PathBuf = [
-        "data",
-        &format!("{:?}", region).to_lowercase(),
-    ].iter().collect();
+    let path_data: PathBuf = queue.path_data(&config.data_root, region);
 
     let path_data_local = {
         let mut x = path_data.clone();
@@ -134,73 +191,184 @@ async fn run_async(region: Region, update_size: usize, pull_ranks: bool) -> Resu
     };
 
     fs::create_dir_all(&path_data_local).await?;
+    {
+        let path_data = path_data.clone();
+        task::spawn_blocking(move || source_fs::recover_match_files(path_data)).await??;
+    }
+
+    // Checkpoint of an interrupted previous run, if any.
+    // Files it was writing are repaired first, after the match files are recovered above.
+    let checkpoint = {
+        let path_data = path_data.clone();
+        let path_data_local = path_data_local.clone();
+        task::spawn_blocking(move || -> std::io::Result<Option<checkpoint::Checkpoint>> {
+            let mut checkpoint = checkpoint::read_checkpoint(path_data_local)?;
+            if let Some(checkpoint) = checkpoint.as_mut() {
+                let saved = pipeline::stats::repair_stale_stats(&path_data,
+                    &checkpoint.stale_stats, &mut checkpoint.pending_match_ids)?;
+                println!("[{:?}] Rebuilt stats of {} files from the checkpoint, {} pending matches already saved.",
+                    region, checkpoint.stale_stats.len(), saved);
+            }
+            Ok(checkpoint)
+        }).await??
+    };
+    let resume = checkpoint.is_some();
 
     // Match bitset.
     let match_hbs = tokio::spawn(pipeline::hybitset::read_match_hybitset(path_data_local.clone()));
-    // Oldest (or selected) summoners, for updating.
+    let mut match_watermark = {
+        let path_data_local = path_data_local.clone();
+        task::spawn_blocking(move || watermark::read_watermark(path_data_local)).await??
+    };
+    // Selected summoners, for updating.
     // Unlike normal futures, this starts automatically (it seems).
+    let selection = config.selection()?;
+    let tier_weights = config.tier_weights()?;
+    println!("[{:?}] Selecting summoners by {:?}{}.", region, selection,
+        if tier_weights.is_empty() { "" } else { ", stratified by tier" });
     let oldest_summoners = {
         let path_data_local = path_data_local.clone();
-        task::spawn_blocking(
-            move || source_fs::get_oldest_summoners(path_data_local, update_size))
+        task::spawn_blocking(move || -> std::io::Result<Option<Vec<Summoner>>> {
+            if resume {
+                // Summoners come from the checkpoint instead.
+                return Ok(Some(vec![]));
+            }
+            let all_summoners = source_fs::get_all_summoners(path_data_local)?;
+            Ok(all_summoners.map(|summoners| selection.select(update_size, summoners, now, lookbehind, &tier_weights)))
+        })
     };
     // All ranked summoners.
-    let ranked_summoners = basic::get_ranked_summoners(&RIOT_API, QUEUE_TYPE, region, &path_data_local, pull_ranks);
+    let ranked_summoners = basic::get_ranked_summoners(api, queue.league_queue_type, region, &path_data_local,
+        pull_ranks, config.league_page_batch_size);
 
     // Join match bitset and oldest selected summoners.
     let (match_hbs, oldest_summoners) = tokio::try_join!(match_hbs, oldest_summoners)?;
     let match_hbs = match_hbs.map_err(|e| e as Box<dyn Error>)?;
     let mut match_hbs = match_hbs.unwrap_or_else(|| HyBitSet::new()); // Create new if none saved.
     let oldest_summoners: Vec<Summoner> = match oldest_summoners? {
-        Some(x) => x.collect(),
+        Some(x) => x,
         None => {
             if !pull_ranks {
-                println!("!! No Summoner .csv.gz found. Use --pull-ranks to start new.");
-                std::process::exit(2);
+                return Err(Box::new(PbwError::new(
+                    "No Summoner .csv.gz found. Use --pull-ranks to start new.".to_owned())));
             }
             vec![]
         },
     };
 
-    println!("Obtained oldest summoners, count: {}.", oldest_summoners.len());
+    let (oldest_summoners, scanned, update_summoner_ts, pending_match_ids, stale_stats) = match checkpoint {
+        Some(checkpoint) => {
+            println!("[{:?}] Resuming from checkpoint, scanned {} of {} summoners, {} matches pending.", region,
+                checkpoint.scanned, checkpoint.summoners.len(), checkpoint.pending_match_ids.len());
+            // Pending matches may be missing from the saved bitset.
+            for match_id in checkpoint.pending_match_ids.iter() {
+                match_hbs.insert(*match_id as usize);
+            }
+            (checkpoint.summoners, checkpoint.scanned, checkpoint.update_summoner_ts, checkpoint.pending_match_ids,
+                checkpoint.stale_stats)
+        },
+        None => (oldest_summoners, 0, now.timestamp_millis() as u64, vec![], vec![]),
+    };
+
+    println!("[{:?}] Obtained oldest summoners, count: {}.", region, oldest_summoners.len());
 
     // Get new match IDs via matchlist.
     let mut oldest_summoners: Vec<Summoner> = mapping_api::update_missing_summoner_account_ids(
-        &RIOT_API, region, 20, oldest_summoners).await;
-    println!("Added missing account IDs, cound: {}.", oldest_summoners.len());
-    let update_summoner_ts: u64 = time::epoch_millis();
+        api, region, config.account_id_batch_size, oldest_summoners).await;
+    println!("[{:?}] Added missing account IDs, cound: {}.", region, oldest_summoners.len());
 
+    let mut last_checkpoint = Instant::now();
+    // Checkpoints are written in the background, one at a time.
+    let mut checkpoint_task: Option<task::JoinHandle<std::io::Result<()>>> = None;
     let new_match_ids = mapping_api::get_new_matchids_update_summoner_gpd(
-        &RIOT_API, region, QUEUE, 20, starttime, &mut oldest_summoners, &mut match_hbs).await;
-    println!("Getting new matches, count: {}.", new_match_ids.len());
+        api, region, queue.queue, config.matchlist_batch_size, now, starttime,
+        &mut oldest_summoners, scanned, &mut match_hbs, &mut match_watermark,
+        |summoners, scanned, new_match_ids| {
+            if last_checkpoint.elapsed() < checkpoint::CHECKPOINT_INTERVAL {
+                return;
+            }
+            if let Some(mut task) = checkpoint_task.take() {
+                match (&mut task).now_or_never() {
+                    // Previous checkpoint still writing.
+                    None => {
+                        checkpoint_task = Some(task);
+                        return;
+                    },
+                    Some(Ok(Ok(()))) => (),
+                    Some(Ok(Err(e))) => println!("[{:?}] Failed to write checkpoint: {}.", region, e),
+                    Some(Err(e)) => println!("[{:?}] Failed to write checkpoint: {}.", region, e),
+                }
+            }
+            last_checkpoint = Instant::now();
+            let path_data_local = path_data_local.clone();
+            let summoners = summoners.to_vec();
+            let pending_match_ids = pending_match_ids.iter().chain(new_match_ids).cloned().collect::<Vec<_>>();
+            let stale_stats = stale_stats.clone();
+            checkpoint_task = Some(task::spawn_blocking(move || checkpoint::write_checkpoint(
+                path_data_local, &summoners, scanned, update_summoner_ts, &pending_match_ids, &stale_stats)));
+        }).await;
+    // Finish before the checkpoint below, so it isn't overwritten.
+    if let Some(task) = checkpoint_task {
+        task.await?.unwrap_or_else(|e| println!("[{:?}] Failed to write checkpoint: {}.", region, e));
+    }
+    let new_match_ids: Vec<i64> = pending_match_ids.into_iter().chain(new_match_ids).collect();
+    println!("[{:?}] Getting new matches, count: {}.", region, new_match_ids.len());
+
+    // Matches are now marked as seen in the bitset, so must be in the checkpoint before the bitset is saved.
+    // Tracked while fetching, the checkpoint is updated as matches are saved.
+    let pending_matches = checkpoint::PendingMatches::new(path_data_local.clone(), oldest_summoners.clone(),
+        update_summoner_ts, &new_match_ids, stale_stats);
+    let pending_matches = task::spawn_blocking(move || pending_matches.write().map(|()| pending_matches)).await??;
+    summary.summoners_updated = oldest_summoners.len();
+    summary.new_match_ids = new_match_ids.len();
     // Updated summoners to update in CSV.
-    let mut updated_summoners_by_id = oldest_summoners.into_iter()
-        // TODO extra clone.
-        .map(|summoner| { (summoner.encrypted_summoner_id.clone(), summoner) })
+    // Cloned since the checkpoint still needs them until the run is done.
+    let mut updated_summoners_by_id = oldest_summoners.iter()
+        .map(|summoner| { (summoner.encrypted_summoner_id.clone(), summoner.clone()) })
         .collect::<HashMap<_, _>>();
 
-    let write_match_hbs = pipeline::hybitset::write_match_hybitset(&path_data_local, &match_hbs);
+    // Drop IDs too old to come back from a matchlist.
+    let pruned = match_watermark.prune(&mut match_hbs, watermark::prune_cutoff(starttime));
+    println!("[{:?}] Pruned {} old match IDs from HBS.", region, pruned);
+
+    // Saved right away, pending matches are tracked by the checkpoint.
+    match_hbs.optimize();
+    pipeline::hybitset::write_match_hybitset(&path_data_local, &match_hbs).await?;
+    {
+        let path_data_local = path_data_local.clone();
+        task::spawn_blocking(move || watermark::write_watermark(path_data_local, &match_watermark)).await??;
+    }
 
     // Completion of ranked_summoners map.
     let ranked_summoners = ranked_summoners.await
         .map_err(|e| e as Box<dyn Error>)?;
     let ranked_summoners = Arc::new(ranked_summoners);
 
-    println!("HBS len: {}.", match_hbs.len());
-    println!("HBS density: {}.", match_hbs.density());
-
-    // Read back and update summoners.
-    let write_summoners = {
-        println!("Writing updated summoners.");
-        let ranked_summoners = ranked_summoners.clone();
+    // Rank history, with this pull's changes recorded.
+    let rank_history = {
         let path_data_local = path_data_local.clone();
-        task::spawn_blocking(move || basic::write_summoners(
-            path_data_local, update_summoner_ts, &mut updated_summoners_by_id, ranked_summoners))
+        let ranked_summoners = ranked_summoners.clone();
+        let pull_ts = now.timestamp_millis() as u64;
+        task::spawn_blocking(move || -> std::io::Result<RankHistory> {
+            let mut rank_history = rank_history::read_rank_history(&path_data_local)?;
+            if pull_ranks {
+                let changes = rank_history.record_pull(ranked_summoners.iter(), pull_ts);
+                println!("[{:?}] Recording {} rank changes.", region, changes.len());
+                rank_history::append_rank_history(&path_data_local, &changes)?;
+            }
+            Ok(rank_history)
+        }).await??
     };
+    let rank_history = Arc::new(rank_history);
+
+    println!("[{:?}] HBS len: {}.", region, match_hbs.len());
+    println!("[{:?}] HBS density: {}.", region, match_hbs.density());
+    println!("[{:?}] HBS heap size: {} bytes.", region, match_hbs.heap_size());
 
     // Write rank -> league csv
+    // An interrupted rank pull is already merged over stored ranks, so this is never partial.
     let write_leagues = {
-        println!("Writing leagues.");
+        println!("[{:?}] Writing leagues.", region);
         // TODO: could optimize by onlying doing this when pull_ranks is true.
         let ranked_summoners = ranked_summoners.clone();
         let path_data = path_data.clone();
@@ -208,110 +376,171 @@ async fn run_async(region: Region, update_size: usize, pull_ranks: bool) -> Resu
     };
 
     // Get new match values.
-    // TODO: this should stream (?).
-    let (matches_sender, matches_receiver) = mpsc::unbounded_channel();
-    let matches_mpsc = tokio::spawn(mapping_api::get_matches_mpsc(matches_sender,
-        &RIOT_API, region, 40, new_match_ids));
+    let (fetched_sender, fetched_receiver) = channel::channel(config.channel_capacity);
+    let (ranked_sender, ranked_receiver) = channel::channel(config.channel_capacity);
+    // Batches are large, so only the next one waits while one is written.
+    let (batch_sender, batch_receiver) = channel::channel(1);
+    let depths = vec![ fetched_sender.depth(), ranked_sender.depth(), batch_sender.depth() ];
+    let matches_mpsc = tokio::spawn(mapping_api::get_matches_mpsc(fetched_sender, depths,
+        api, region, config.match_batch_size, new_match_ids));
 
     // let new_matches = new_matches.await;
-    println!("Started getting matches.");
+    println!("[{:?}] Started getting matches.", region);
 
     // Handle matches.
-    // Matches grouped by their file key for convenient access.
-    let grouped_new_matches = handle_matches(matches_receiver, ranked_summoners.clone()).await;
+    // Matches are written to their files in batches, with each batch's stats.
+    let match_sink = MatchSink::new(path_data.clone());
+    let stages = tokio::try_join!(
+        match_stages::rank_stage(fetched_receiver, ranked_sender, region, ranked_summoners.clone(), rank_history),
+        match_stages::stats_stage(ranked_receiver, batch_sender),
+        match_stages::write_stage(batch_receiver, match_sink, pending_matches));
+    // Summoner updates from the scan are written even if the match stages failed, just without participants.
+    let (participants, stages) = match stages {
+        Ok((participants, (), files_written)) => {
+            println!("[{:?}] Harvested {} match participants.", region, participants.len());
+            (participants, Ok(files_written))
+        },
+        Err(e) => (HashMap::new(), Err(e)),
+    };
+
+    // Read back and update summoners, adding participants not seen before.
+    // After matches, since participants come from them.
+    let write_summoners = {
+        println!("[{:?}] Writing updated summoners.", region);
+        let ranked_summoners = ranked_summoners.clone();
+        let path_data_local = path_data_local.clone();
+        task::spawn_blocking(move || basic::write_summoners(
+            path_data_local, update_summoner_ts, &mut updated_summoners_by_id, ranked_summoners, participants))
+    };
+
+    write_summoners.await?.map_err(|e| e as Box<dyn Error>)?;
+    summary.match_files_written = stages.map_err(|e| e as Box<dyn Error>)?;
 
     // Collect any errors from matches mpsc.
-    {
-        let count = matches_mpsc.await??;
-        println!("Fetched {} matches.", count);
+    let failed = {
+        let (count, failed) = matches_mpsc.await??;
+        println!("[{:?}] Fetched {} matches.", region, count);
+        summary.matches_fetched = count;
+        failed
+    };
+
+    write_leagues.await??;
+
+    if shutdown::requested() {
+        // Unfetched matches remain in the checkpoint for the next run.
+        println!("[{:?}] Interrupted, checkpoint kept.", region);
+        return Ok(summary);
+    }
+    if !failed.is_empty() {
+        // Already marked as seen in the bitset, so only the checkpoint has them.
+        println!("[{:?}] Failed to fetch {} matches, checkpoint kept to retry them.", region, failed.len());
+        return Ok(summary);
     }
 
-    // let grouped_new_matches = new_matches.into_iter()
-    //     .map(|matche| (MatchFileKey::from(&matche), matche))
-    //     .into_group_map();
-
-    let mut write_matches_tasks = Vec::with_capacity(grouped_new_matches.len());
-
-    for (match_key, model_matches) in grouped_new_matches {
-        let version = match_key.version;
-        let iso_week = match_key.iso_week;
-
-        // Create directory (if not exists) for this patch.
-        let mut path_data_key = path_data.clone();
-        path_data_key.push(format!("{}.{}", version.0, version.1));
-        fs::create_dir_all(&path_data_key).await?;
-
-        // let model_matches = matches.iter()
-        //     .map(|matche| {
-        //         let tiers = matche.participant_identities.iter()
-        //             .map(|participant| {
-        //                 ranked_summoners.get(&participant.player.summoner_id)
-        //                     .map(|(tier, _league_id)| tier)
-        //                     .cloned()
-        //             });
-        //         let avg_tier = util::lol::match_avg_tier(tiers);
-        //         Match {
-        //             match_id: matche.game_id as u64,
-        //             rank_tier: avg_tier,
-        //             ts: matche.game_creation as u64,
-        //         }
-        //     })
-        //     .collect::<Vec<_>>();
-
-        let iso_week_str = format!("{:04}-W{:02}", iso_week.0, iso_week.1);
-
-        let write_matches = task::spawn_blocking(
-            move || source_fs::write_matches(&path_data_key, &iso_week_str, model_matches.iter()));
-        write_matches_tasks.push(write_matches);
+    // Everything is saved.
+    checkpoint::remove_checkpoint(&path_data_local)?;
+
+    println!("[{:?}] Done.", region);
+    Ok(summary)
+}
+
+// Data dirs of `region`, or of all regions found for the configured queue.
+fn region_paths(config: &Config, region: Option<Region>) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let queue = config.queue()?;
+    let path_datas: Vec<PathBuf> = match region {
+        Some(region) => vec![ queue.path_data(&config.data_root, region) ],
+        None => std::fs::read_dir(queue.queue_root(&config.data_root))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter(|path| path.is_dir())
+            // Only region dirs, skips other queues' trees nested in the solo queue root.
+            .filter(|path| path.file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.to_uppercase().parse::<Region>().is_ok())
+                .unwrap_or(false))
+            .collect(),
     };
+    Ok(path_datas)
+}
 
-    // Join not needed since both are already started.
-    for res in join_all(write_matches_tasks).await {
-        res??;
+// Rebuilds all stats files from stored match files, without the API.
+fn recompute(config: &Config, region: Option<Region>) -> Result<(), Box<dyn Error>> {
+    for path_data in region_paths(config, region)? {
+        println!("Recomputing stats in {:?}.", path_data);
+        let (files, count) = pipeline::stats::recompute_stats(&path_data)?;
+        println!("Wrote {} stats files from {} matches.", files, count);
     }
-    write_summoners.await?.map_err(|e| e as Box<dyn Error>)?;
-    write_leagues.await??;
-    write_match_hbs.await?;
 
     println!("Done.");
     Ok(())
 }
 
-async fn handle_matches(mut matches_receiver: mpsc::UnboundedReceiver<match_v4::Match>,
-    ranked_summoners: Arc<HashMap<String, (Tier, String)>>)
-    -> HashMap<MatchFileKey, Vec<Match>>
-{
-    let mut out = HashMap::new();
-    while let Some(matche) = matches_receiver.recv().await {
-        let match_key = MatchFileKey::from(&matche);
-
-        let tiers = matche.participant_identities.iter()
-            .map(|participant| {
-                ranked_summoners.get(&participant.player.summoner_id)
-                    .map(|(tier, _league_id)| tier)
-                    .cloned()
-            });
-        let avg_tier = util::lol::match_avg_tier(tiers);
+// Removes old summoner and hybitset snapshots and rewrites multi-member match files.
+fn compact(config: &Config, region: Option<Region>) -> Result<(), Box<dyn Error>> {
+    let now = Utc::now();
+    let retention = config.retention();
+    let mut total = CompactSummary::default();
+    for path_data in region_paths(config, region)? {
+        println!("Compacting {:?}.", path_data);
+        let path_data_local = path_data.join("local");
+
+        let mut summary = CompactSummary::default();
+        if path_data_local.is_dir() {
+            summary.add(&compact::compact_snapshots(&path_data_local, "summoner", "csv.gz", now, retention)?);
+            for ext in &[ pipeline::hybitset::FILE_EXT, pipeline::hybitset::FILE_EXT_JSON ] {
+                summary.add(&compact::compact_snapshots(
+                    &path_data_local, pipeline::hybitset::FILE_TAG, ext, now, retention)?);
+            }
+        }
+        summary.add(&compact::compact_match_files(&path_data)?);
+        println!("  {}", summary);
+        total.add(&summary);
+    }
+
+    println!("Done, {}", total);
+    Ok(())
+}
+
+// Platform regions used by `all`.
+const ALL_REGIONS: [Region; 11] = [
+    Region::BR, Region::EUNE, Region::EUW, Region::JP, Region::KR, Region::LAN,
+    Region::LAS, Region::NA, Region::OCE, Region::RU, Region::TR,
+];
+
+// Parses a comma-separated list of regions, or `all`.
+// Duplicates are dropped (keeping the first), since two runs of a region would share its files.
+fn parse_regions(regions_str: &str) -> Vec<Region> {
+    if "all" == regions_str.to_lowercase() {
+        return ALL_REGIONS.to_vec();
+    }
+    let mut regions = vec![];
+    for region in regions_str.split(',').map(|region_str| parse_region(region_str.trim())) {
+        if !regions.contains(&region) {
+            regions.push(region);
+        }
+    }
+    regions
+}
 
-        let vec = out.entry(match_key).or_insert_with(Vec::new);
-        vec.push(Match {
-            match_id: matche.game_id as u64,
-            rank_tier: avg_tier,
-            ts: matche.game_creation as u64,
+// Case-insensitive, like `all`.
+fn parse_region(region_str: &str) -> Region {
+    region_str.to_uppercase().parse()
+        .unwrap_or_else(|_e| {
+            println!("Unknown region: {}.", region_str);
+            std::process::exit(1);
         })
-    };
-    out
 }
 
 pub fn main() {
-    use clap::{ Arg, App };
+    use clap::{ Arg, App, SubCommand };
 
     let argparse = App::new("pickban.win script")
         .version("0.1.0")
         .about("Gets data from Riot API.")
         .arg(Arg::with_name("region")
             .takes_value(true)
-            .help("Region to run on.")
+            .help("Region(s) to run on, comma-separated, or all.")
             .index(1))
         .arg(Arg::with_name("update size")
             .takes_value(true)
@@ -320,14 +549,72 @@ pub fn main() {
         .arg(Arg::with_name("pull ranks")
             .long("pull-ranks")
             .takes_value(false))
+        .arg(Arg::with_name("config")
+            .long("config")
+            .takes_value(true)
+            .value_name("FILE")
+            .global(true)
+            .help("TOML config file, default pickbanwin.toml (if exists)."))
+        .args(&config::KEYS.iter()
+            .map(|&(key, flag)| Arg::with_name(key)
+                .long(flag)
+                .takes_value(true)
+                .global(true)
+                .help("Overrides config value."))
+            .collect::<Vec<_>>())
+        .arg(Arg::with_name("record")
+            .long("record")
+            .takes_value(true)
+            .value_name("DIR")
+            .conflicts_with("replay")
+            .help("Record all API responses as JSON files in DIR."))
+        .arg(Arg::with_name("replay")
+            .long("replay")
+            .takes_value(true)
+            .value_name("DIR")
+            .help("Serve API responses from recorded JSON files in DIR instead of the Riot API. \
+                Requires a data_root other than the default."))
+        .subcommand(SubCommand::with_name("recompute")
+            .about("Rebuilds stats files from stored match files.")
+            .arg(Arg::with_name("region")
+                .takes_value(true)
+                .help("Region to recompute, or all if omitted.")
+                .index(1)))
+        .subcommand(SubCommand::with_name("compact")
+            .about("Removes old summoner and hybitset snapshots, rewrites multi-member match files.")
+            .arg(Arg::with_name("region")
+                .takes_value(true)
+                .help("Region to compact, or all if omitted.")
+                .index(1)))
         .get_matches();
 
-    let region_str = argparse.value_of("region").unwrap();
-    let region: Region = region_str.parse()
-        .unwrap_or_else(|_e| {
-            println!("Unknown region: {}.", region_str);
-            std::process::exit(1);
-        });
+    let config = {
+        let argparse = argparse.subcommand_matches("recompute")
+            .or(argparse.subcommand_matches("compact"))
+            .unwrap_or(&argparse);
+        let cli_overrides = config::KEYS.iter()
+            .filter_map(|&(key, _flag)| argparse.value_of(key).map(|val| (key, val)));
+        Config::load(argparse.value_of("config").map(std::path::Path::new), cli_overrides)
+            .unwrap_or_else(|e| {
+                println!("{}", e);
+                std::process::exit(1);
+            })
+    };
+
+    if let Some(argparse) = argparse.subcommand_matches("recompute") {
+        let region = argparse.value_of("region").map(parse_region);
+        recompute(&config, region)
+            .unwrap_or_else(|e| panic!("Failed to recompute: {}", e));
+        return;
+    }
+    if let Some(argparse) = argparse.subcommand_matches("compact") {
+        let region = argparse.value_of("region").map(parse_region);
+        compact(&config, region)
+            .unwrap_or_else(|e| panic!("Failed to compact: {}", e));
+        return;
+    }
+
+    let regions = parse_regions(argparse.value_of("region").unwrap());
 
     let update_size_str = argparse.value_of("update size").unwrap();
     let update_size: usize = update_size_str.parse()
@@ -338,7 +625,66 @@ pub fn main() {
 
     let pull_ranks = argparse.is_present("pull ranks");
 
+    // APIs are leaked since they live for the whole program.
+    let riot_api = || -> &'static RiotApi {
+        let riot_api_config = config.riot_api_config()
+            .unwrap_or_else(|e| {
+                println!("{}", e);
+                std::process::exit(1);
+            });
+        Box::leak(Box::new(RiotApi::with_config(riot_api_config)))
+    };
+    let (api, now): (&'static dyn Api, DateTime<Utc>) =
+        if let Some(dir) = argparse.value_of("replay") {
+            // So replayed data never mixes with live data, wherever data_root was set.
+            if Config::default().data_root == config.data_root {
+                println!("Replay requires a data_root other than the default {:?}.", config.data_root);
+                std::process::exit(1);
+            }
+            // Use the recorded start time so time windows match the recording.
+            let now = match RunInfo::read(dir).expect("Failed to read recorded run info.") {
+                Some(run_info) => Utc.timestamp_millis(run_info.now_millis),
+                None => {
+                    println!("No recorded run info in {}, cannot replay deterministically.", dir);
+                    std::process::exit(1);
+                },
+            };
+            let api: &'static dyn Api = Box::leak(Box::new(FixtureApi::new(dir)));
+            (api, now)
+        }
+        else if let Some(dir) = argparse.value_of("record") {
+            let now = Utc::now();
+            RunInfo { now_millis: now.timestamp_millis() }.write(dir)
+                .expect("Failed to write recorded run info.");
+            let api: &'static dyn Api = Box::leak(Box::new(RecordingApi::new(riot_api(), dir)));
+            (api, now)
+        }
+        else {
+            (riot_api(), Utc::now())
+        };
+
     let mut rt = tokio::runtime::Runtime::new().unwrap();
-    rt.block_on(run_async(region, update_size, pull_ranks))
-        .unwrap_or_else(|e| panic!("Failed to complete: {}", e));
+    let ok = rt.block_on(async {
+        shutdown::install();
+        run_regions_async(api, &config, now, &regions, update_size, pull_ranks).await
+    });
+    if !ok {
+        std::process::exit(1);
+    }
+    if shutdown::requested() {
+        println!("Interrupted, resume by running again.");
+        std::process::exit(shutdown::EXIT_INTERRUPTED);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_regions() {
+        assert_eq!(vec![ Region::NA, Region::EUW ], parse_regions("na,euw,na"));
+        assert_eq!(vec![ Region::EUW, Region::NA ], parse_regions("euw, na, na"));
+        assert_eq!(ALL_REGIONS.to_vec(), parse_regions("ALL"));
+    }
 }
@@ -1,13 +1,185 @@
+use std::fmt;
+use std::str::FromStr;
+
 use serde::{Serialize, Deserialize};
-use riven::consts::Tier;
+use riven::consts::{ Division, Tier };
 
+use crate::util::error::PbwError;
 use crate::util::time;
 
+// Current on-disk schema version of `Match` rows.
+// Version 1: `match_id,rank_tier,ts` only, no `schema` column.
+// Version 2: adds game version, duration, winner, picks and bans.
+// Version 3: adds average division and ladder score, see `util::lol::rank_score`.
+pub const MATCH_SCHEMA_VERSION: u8 = 3;
+
+// CSV header of the current schema. Files with any other header are rewritten before appending.
+pub const MATCH_CSV_HEADER: [&'static str; 11] = [
+    "match_id", "rank_tier", "ts", "schema", "game_version", "game_duration",
+    "winner", "picks", "bans", "rank_division", "rank_score",
+];
+
+fn match_schema_v1() -> u8 {
+    1
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Match {
     pub match_id: u64,
     pub rank_tier: Option<Tier>,
     pub ts: u64,
+    #[serde(default = "match_schema_v1")]
+    pub schema: u8,
+    #[serde(default)]
+    pub game_version: Option<String>,
+    // Seconds.
+    #[serde(default)]
+    pub game_duration: Option<u32>,
+    // Winning team ID (100 or 200).
+    #[serde(default)]
+    pub winner: Option<u8>,
+    #[serde(default, with = "space_separated")]
+    pub picks: Vec<Pick>,
+    #[serde(default, with = "space_separated")]
+    pub bans: Vec<Ban>,
+    #[serde(default)]
+    pub rank_division: Option<Division>,
+    #[serde(default)]
+    pub rank_score: Option<u16>,
+}
+
+impl Match {
+    // `rank_score` is the participants' average ladder score, `rank_tier` and `rank_division` are derived from it.
+    pub fn from_api(matche: &match_v4::Match, rank_score: Option<u16>) -> Self {
+        let rank = rank_score.map(crate::util::lol::score_rank);
+        let picks = matche.participants.iter()
+            .map(|participant| Pick {
+                champion_id: participant.champion_id as i16,
+                team: participant.team_id as u8,
+                role: participant.timeline.role.clone(),
+                lane: participant.timeline.lane.clone(),
+            })
+            .collect();
+        let bans = matche.teams.iter()
+            .flat_map(|team| team.bans.iter().map(move |ban| Ban {
+                champion_id: ban.champion_id as i16,
+                team: team.team_id as u8,
+                pick_turn: ban.pick_turn as u8,
+            }))
+            .collect();
+        let winner = matche.participants.iter()
+            .find(|participant| participant.stats.win)
+            .map(|participant| participant.team_id as u8);
+
+        Self {
+            match_id: matche.game_id as u64,
+            rank_tier: rank.map(|(tier, _)| tier),
+            ts: matche.game_creation as u64,
+            schema: MATCH_SCHEMA_VERSION,
+            game_version: Some(matche.game_version.clone()),
+            game_duration: Some(matche.game_duration as u32),
+            winner: winner,
+            picks: picks,
+            bans: bans,
+            rank_division: rank.and_then(|(_, division)| division),
+            rank_score: rank_score,
+        }
+    }
+}
+
+// Single champion pick, stored as `champion_id/team/role/lane`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pick {
+    pub champion_id: i16,
+    pub team: u8,
+    pub role: String,
+    pub lane: String,
+}
+
+impl fmt::Display for Pick {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}/{}/{}", self.champion_id, self.team, self.role, self.lane)
+    }
+}
+
+impl FromStr for Pick {
+    type Err = PbwError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || PbwError::new(format!("Invalid pick: {}.", s));
+        let mut split = s.split('/');
+        let champion_id = split.next().and_then(|x| x.parse().ok()).ok_or_else(err)?;
+        let team = split.next().and_then(|x| x.parse().ok()).ok_or_else(err)?;
+        let role = split.next().ok_or_else(err)?.to_owned();
+        let lane = split.next().ok_or_else(err)?.to_owned();
+        Ok(Self {
+            champion_id: champion_id,
+            team: team,
+            role: role,
+            lane: lane,
+        })
+    }
+}
+
+// Single champion ban, stored as `champion_id/team/pick_turn`.
+// Negative `champion_id` means no ban.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub champion_id: i16,
+    pub team: u8,
+    pub pick_turn: u8,
+}
+
+impl fmt::Display for Ban {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}/{}", self.champion_id, self.team, self.pick_turn)
+    }
+}
+
+impl FromStr for Ban {
+    type Err = PbwError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || PbwError::new(format!("Invalid ban: {}.", s));
+        let mut split = s.split('/');
+        let champion_id = split.next().and_then(|x| x.parse().ok()).ok_or_else(err)?;
+        let team = split.next().and_then(|x| x.parse().ok()).ok_or_else(err)?;
+        let pick_turn = split.next().and_then(|x| x.parse().ok()).ok_or_else(err)?;
+        Ok(Self {
+            champion_id: champion_id,
+            team: team,
+            pick_turn: pick_turn,
+        })
+    }
+}
+
+// (De)serializes a list as a single space-separated CSV field.
+mod space_separated {
+    use std::fmt::Display;
+    use std::str::FromStr;
+
+    use itertools::Itertools;
+    use serde::{ Deserialize, Deserializer, Serializer };
+
+    pub fn serialize<T, S>(items: &Vec<T>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Display,
+        S: Serializer,
+    {
+        serializer.serialize_str(&items.iter().join(" "))
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Vec<T>, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.split_whitespace()
+            .map(|item| item.parse().map_err(serde::de::Error::custom))
+            .collect()
+    }
 }
 
 
@@ -15,6 +187,7 @@ use chrono::{ Datelike, DateTime };
 use chrono::offset::Utc;
 use riven::models::match_v4;
 
+#[derive(Clone, Copy, Debug)]
 #[derive(Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct MatchFileKey {
     pub version: (u8, u8),
@@ -22,6 +195,18 @@ pub struct MatchFileKey {
     // pub tier: Tier,
 }
 
+impl MatchFileKey {
+    // Patch dir name of the key's files, `<major.minor>`.
+    pub fn patch_str(&self) -> String {
+        format!("{}.{}", self.version.0, self.version.1)
+    }
+
+    // ISO week infix of the key's files, `YYYY-Www`.
+    pub fn iso_week_str(&self) -> String {
+        format!("{:04}-W{:02}", self.iso_week.0, self.iso_week.1)
+    }
+}
+
 impl From<&match_v4::Match> for MatchFileKey {
     fn from(matche: &match_v4::Match) -> Self {
         let version = crate::util::lol::parse_version(&matche.game_version)
@@ -36,3 +221,72 @@ impl From<&match_v4::Match> for MatchFileKey {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_v1() {
+        let data = "match_id,rank_tier,ts\n3300000001,GOLD,1582700000000\n";
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let matche: Match = reader.deserialize().next().unwrap().unwrap();
+        assert_eq!(3300000001, matche.match_id);
+        assert_eq!(Some(Tier::GOLD), matche.rank_tier);
+        assert_eq!(1, matche.schema);
+        assert_eq!(None, matche.winner);
+        assert!(matche.picks.is_empty());
+        assert!(matche.bans.is_empty());
+        assert_eq!(None, matche.rank_score);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let matche = Match {
+            match_id: 3300000002,
+            rank_tier: None,
+            ts: 1582700000000,
+            schema: MATCH_SCHEMA_VERSION,
+            game_version: Some("10.4.311.9134".to_owned()),
+            game_duration: Some(1834),
+            winner: Some(200),
+            picks: vec![
+                Pick { champion_id: 266, team: 100, role: "SOLO".to_owned(), lane: "TOP".to_owned() },
+                Pick { champion_id: 412, team: 200, role: "DUO_SUPPORT".to_owned(), lane: "BOTTOM".to_owned() },
+            ],
+            bans: vec![
+                Ban { champion_id: 350, team: 100, pick_turn: 1 },
+                Ban { champion_id: -1, team: 200, pick_turn: 6 },
+            ],
+            rank_division: Some(Division::II),
+            rank_score: Some(1875),
+        };
+
+        let mut writer = csv::Writer::from_writer(vec![]);
+        writer.serialize(&matche).unwrap();
+        let data = writer.into_inner().unwrap();
+
+        let mut reader = csv::Reader::from_reader(&*data);
+        assert_eq!(MATCH_CSV_HEADER.to_vec(), reader.headers().unwrap().iter().collect::<Vec<_>>());
+        let read: Match = reader.deserialize().next().unwrap().unwrap();
+        assert_eq!(MATCH_SCHEMA_VERSION, read.schema);
+        assert_eq!(matche.game_version, read.game_version);
+        assert_eq!(matche.winner, read.winner);
+        assert_eq!(matche.picks, read.picks);
+        assert_eq!(matche.bans, read.bans);
+        assert_eq!(matche.rank_division, read.rank_division);
+        assert_eq!(matche.rank_score, read.rank_score);
+    }
+
+    #[test]
+    fn test_read_v2() {
+        let data = "match_id,rank_tier,ts,schema,game_version,game_duration,winner,picks,bans\n\
+            3300000003,SILVER,1582700000000,2,10.4.311.9134,1834,100,266/100/SOLO/TOP,350/100/1\n";
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let matche: Match = reader.deserialize().next().unwrap().unwrap();
+        assert_eq!(2, matche.schema);
+        assert_eq!(Some(Tier::SILVER), matche.rank_tier);
+        assert_eq!(None, matche.rank_division);
+        assert_eq!(None, matche.rank_score);
+    }
+}
@@ -1,3 +1,5 @@
 pub mod league;
 pub mod r#match;
+pub mod rank;
+pub mod stats;
 pub mod summoner;
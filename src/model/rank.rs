@@ -0,0 +1,40 @@
+use serde::{Serialize, Deserialize};
+use riven::consts::{ Division, Tier };
+
+// A summoner's current standing in the crawled queue.
+// `division` and `league_points` may be missing for summoners stored before they were kept.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Rank {
+    pub tier: Tier,
+    pub division: Option<Division>,
+    pub league_points: Option<i32>,
+    pub league_id: String,
+}
+
+// Row of `rank_history.csv.gz`, written when a pull finds a summoner's rank changed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RankChange {
+    pub summoner_id: String,
+    // Time of the pull, epoch millis.
+    pub ts: u64,
+    pub tier: Tier,
+    pub division: Option<Division>,
+    pub league_points: Option<i32>,
+}
+
+impl RankChange {
+    pub fn new(summoner_id: String, ts: u64, rank: &Rank) -> Self {
+        Self {
+            summoner_id: summoner_id,
+            ts: ts,
+            tier: rank.tier,
+            division: rank.division,
+            league_points: rank.league_points,
+        }
+    }
+
+    // Same standing, ignoring summoner and time.
+    pub fn same_rank(&self, rank: &Rank) -> bool {
+        self.tier == rank.tier && self.division == rank.division && self.league_points == rank.league_points
+    }
+}
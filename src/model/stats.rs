@@ -0,0 +1,15 @@
+use serde::{Serialize, Deserialize};
+use riven::consts::Tier;
+
+// Single row of a `stats.<iso_week>.csv.gz` file.
+// `games` is the total number of matches in the `rank_tier` bucket
+// (same for every champion in the bucket), for pick/ban rates.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChampStats {
+    pub rank_tier: Option<Tier>,
+    pub champion_id: i16,
+    pub games: u32,
+    pub picks: u32,
+    pub bans: u32,
+    pub wins: u32,
+}
@@ -1,12 +1,17 @@
 use serde::{Serialize, Deserialize};
-use riven::consts::Tier;
+use riven::consts::{ Division, Tier };
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Summoner {
     pub encrypted_summoner_id: String,
     pub encrypted_account_id:  Option<String>,
     pub league_id: Option<String>,
     pub rank_tier: Option<Tier>,
+    // Missing in summoner files written before divisions were kept.
+    #[serde(default)]
+    pub rank_division: Option<Division>,
+    #[serde(default)]
+    pub league_points: Option<i32>,
     pub games_per_day: Option<f32>,
     pub ts: Option<u64>,
 }
@@ -32,6 +37,29 @@ impl PartialEq for SummonerOldest {
 impl Eq for SummonerOldest {}
 
 
+// Summoner with its expected number of unseen matches, higher yield sorts first.
+// The yield must not be NaN (`selection::expected_yield` never is), otherwise the order isn't total.
+pub struct SummonerYield(pub f32, pub Summoner);
+impl Ord for SummonerYield {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // Reversed, so `filter_min_n` keeps the highest yields.
+        other.0.partial_cmp(&self.0).unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| self.1.ts.cmp(&other.1.ts))
+    }
+}
+impl PartialOrd for SummonerYield {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl PartialEq for SummonerYield {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+impl Eq for SummonerYield {}
+
+
 pub struct SummonerHighestRanked(pub Summoner);
 impl Ord for SummonerHighestRanked {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
@@ -62,6 +90,8 @@ mod test {
             encrypted_account_id:  None,
             league_id: None,
             rank_tier: None,
+            rank_division: None,
+            league_points: None,
             games_per_day: None,
             ts: None,
         };
@@ -70,6 +100,8 @@ mod test {
             encrypted_account_id:  None,
             league_id: None,
             rank_tier: None,
+            rank_division: None,
+            league_points: None,
             games_per_day: None,
             ts: Some(100),
         };
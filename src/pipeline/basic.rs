@@ -2,78 +2,95 @@ use std::collections::{ BTreeSet, HashMap };
 use std::error::Error;
 use std::path::{ Path, PathBuf };
 
-use riven::{ RiotApi };
-use riven::consts::{ Region, Tier, QueueType };
+use riven::consts::{ Region, QueueType };
 use tokio::task;
 
+use crate::api::Api;
 use crate::dyn_err;
 use crate::model::summoner::Summoner;
 use crate::model::league::League;
+use crate::model::rank::Rank;
 use crate::pipeline::{ source_fs, source_api };
+use crate::util::shutdown;
 
-pub async fn get_ranked_summoners(riot_api: &'static RiotApi, queue_type: QueueType,
-    region: Region, path_data_local: &PathBuf, pull_ranks: bool)
-    -> Result<HashMap<String, (Tier, String)>, Box<dyn Error + Send>>
+// Ranks from the API if `pull_ranks`, otherwise stored ones.
+// A pull interrupted by shutdown is partial, so it is merged over the stored ranks.
+pub async fn get_ranked_summoners(riot_api: &'static dyn Api, queue_type: QueueType,
+    region: Region, path_data_local: &PathBuf, pull_ranks: bool, pagination_batch_size: usize)
+    -> Result<HashMap<String, Rank>, Box<dyn Error + Send>>
 {
-    let pagination_batch_size: usize = 10;
-    if pull_ranks {
+    let pulled = if pull_ranks {
         let future = tokio::spawn(source_api::get_ranked_summoners(
             riot_api, queue_type, region, pagination_batch_size));
         let hashmap = future.await.map_err(dyn_err)?;
-        Ok(hashmap)
+        if !shutdown::requested() {
+            return Ok(hashmap);
+        }
+        println!("[{:?}] Rank pull interrupted, merging {} pulled ranks over stored ranks.", region, hashmap.len());
+        Some(hashmap)
     } else {
-        let path_data_local = path_data_local.clone();
-        let future = task::spawn_blocking(move || source_fs::get_ranked_summoners(path_data_local));
-        let hashmap = future.await
-            .map_err(dyn_err)?
-            .map_err(dyn_err)?;
-        Ok(hashmap)
+        None
+    };
+
+    let path_data_local = path_data_local.clone();
+    let future = task::spawn_blocking(move || source_fs::get_ranked_summoners(path_data_local));
+    let mut hashmap = future.await
+        .map_err(dyn_err)?
+        .map_err(dyn_err)?;
+    if let Some(pulled) = pulled {
+        hashmap.extend(pulled);
     }
+    Ok(hashmap)
 }
 
 pub fn write_league_ids<RS>(path_data: impl AsRef<Path>, ranked_summoners: RS)
     -> std::io::Result<()>
 where
-    RS: AsRef<HashMap<String, (Tier, String)>>
+    RS: AsRef<HashMap<String, Rank>>
 {
     let mut leagues = BTreeSet::new();
-    for (tier, league_id) in ranked_summoners.as_ref().values() {
+    for rank in ranked_summoners.as_ref().values() {
         leagues.insert(League {
-            league_id: league_id.clone(), //TODO extra clone.
-            tier: *tier,
+            league_id: rank.league_id.clone(), //TODO extra clone.
+            tier: rank.tier,
         });
     };
     source_fs::write_leagues(path_data, leagues.into_iter().rev())
 }
 
+// `discovered_summoners` maps summoner IDs to account IDs of match participants.
+// Known ones missing an account ID get it filled, then the rest are added in a second pass.
 pub fn write_summoners<RS>(path: impl AsRef<Path>, update_summoner_ts: u64,
     updated_summoners_by_id: &mut HashMap<String, Summoner>,
-    ranked_summoners: RS)
+    ranked_summoners: RS, mut discovered_summoners: HashMap<String, String>)
     -> Result<(), Box<dyn Error + Send>>
 where
-    RS: AsRef<HashMap<String, (Tier, String)>>
+    RS: AsRef<HashMap<String, Rank>>
 {
     let all_summoners = source_fs::get_all_summoners(&path).map_err(dyn_err)?;
+    let ranked_summoners = ranked_summoners.as_ref();
 
-    match all_summoners {
+    // First pass, known summoners.
+    let known_summoners: Vec<Summoner> = match all_summoners {
         None => { // THERES NO SUMMONER .CSV.GZ TO READ FROM!
             assert!(updated_summoners_by_id.is_empty(), "all_summoners empty but updated_summoners_by_id not empty.");
 
-            let summoner_models = ranked_summoners.as_ref().iter()
-                .map(|(summoner_id, (tier, league_id))| Summoner {
+            ranked_summoners.iter()
+                .map(|(summoner_id, rank)| Summoner {
                     encrypted_summoner_id: summoner_id.clone(), // TODO extra clone.
-                    encrypted_account_id: None,
-                    league_id: Some(league_id.clone()), // TODO extra clone.
-                    rank_tier: Some(*tier),
+                    encrypted_account_id: discovered_summoners.remove(summoner_id),
+                    league_id: Some(rank.league_id.clone()), // TODO extra clone.
+                    rank_tier: Some(rank.tier),
+                    rank_division: rank.division,
+                    league_points: rank.league_points,
                     games_per_day: None,
                     ts: None,
-                });
-
-            source_fs::write_summoners(&path, summoner_models).map_err(dyn_err)?;
+                })
+                .collect()
         },
         Some(all_summoners) => {
             // Set timestamps on updated summoner.
-            let all_summoners = all_summoners.map(move |mut summoner| {
+            all_summoners.map(|mut summoner| {
                 // Update timestamp and games per day (TODO).
                 if let Some(updated_summoner) = updated_summoners_by_id.remove(&summoner.encrypted_summoner_id) {
                     summoner.ts = Some(update_summoner_ts);
@@ -82,16 +99,41 @@ where
                     // TODO update any other things.
                 }
                 // Update tiers.
-                if let Some((tier, league_id)) = ranked_summoners.as_ref().get(&summoner.encrypted_summoner_id) {
-                    summoner.rank_tier = Some(*tier);
-                    summoner.league_id = Some(league_id.clone()); // TODO bad copy.
+                if let Some(rank) = ranked_summoners.get(&summoner.encrypted_summoner_id) {
+                    summoner.rank_tier = Some(rank.tier);
+                    summoner.rank_division = rank.division;
+                    summoner.league_points = rank.league_points;
+                    summoner.league_id = Some(rank.league_id.clone()); // TODO bad copy.
+                }
+                // Known, so not new.
+                if let Some(account_id) = discovered_summoners.remove(&summoner.encrypted_summoner_id) {
+                    if summoner.encrypted_account_id.is_none() {
+                        summoner.encrypted_account_id = Some(account_id);
+                    }
                 }
                 summoner
-            });
-
-            // Write summoners job.
-            source_fs::write_summoners(&path, all_summoners).map_err(dyn_err)?;
+            })
+            .collect()
         },
     };
+
+    // Second pass, discovered summoners not known above.
+    let new_summoners = discovered_summoners.into_iter()
+        .map(|(summoner_id, account_id)| {
+            let rank = ranked_summoners.get(&summoner_id);
+            Summoner {
+                league_id: rank.map(|rank| rank.league_id.clone()),
+                rank_tier: rank.map(|rank| rank.tier),
+                rank_division: rank.and_then(|rank| rank.division),
+                league_points: rank.and_then(|rank| rank.league_points),
+                encrypted_summoner_id: summoner_id,
+                encrypted_account_id: Some(account_id),
+                games_per_day: None,
+                ts: None,
+            }
+        });
+
+    // Write summoners job.
+    source_fs::write_summoners(&path, known_summoners.into_iter().chain(new_summoners)).map_err(dyn_err)?;
     Ok(())
 }
@@ -0,0 +1,85 @@
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+
+use tokio::sync::mpsc;
+
+// Bounded mpsc channel which tracks how many items are queued, for progress output.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let (sender, receiver) = mpsc::channel(capacity);
+    let depth = Depth(Arc::new(AtomicUsize::new(0)));
+    (
+        Sender { inner: sender, depth: depth.clone() },
+        Receiver { inner: receiver, depth: depth },
+    )
+}
+
+// Handle to a channel's current queue depth.
+#[derive(Clone)]
+pub struct Depth(Arc<AtomicUsize>);
+
+impl Depth {
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Sender<T> {
+    inner: mpsc::Sender<T>,
+    depth: Depth,
+}
+
+impl<T> Sender<T> {
+    // Waits while the channel is full.
+    pub async fn send(&mut self, value: T) -> Result<(), mpsc::error::SendError<T>> {
+        // Increment first so the receiver never sees a negative depth.
+        (self.depth.0).fetch_add(1, Ordering::Relaxed);
+        let result = self.inner.send(value).await;
+        if result.is_err() {
+            (self.depth.0).fetch_sub(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    pub fn depth(&self) -> Depth {
+        self.depth.clone()
+    }
+}
+
+pub struct Receiver<T> {
+    inner: mpsc::Receiver<T>,
+    depth: Depth,
+}
+
+impl<T> Receiver<T> {
+    pub async fn recv(&mut self) -> Option<T> {
+        let value = self.inner.recv().await;
+        if value.is_some() {
+            (self.depth.0).fetch_sub(1, Ordering::Relaxed);
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_depth() {
+        let (mut sender, mut receiver) = channel(4);
+        let depth = sender.depth();
+        assert_eq!(0, depth.get());
+
+        sender.send(1).await.unwrap();
+        sender.send(2).await.unwrap();
+        assert_eq!(2, depth.get());
+
+        assert_eq!(Some(1), receiver.recv().await);
+        assert_eq!(1, depth.get());
+
+        drop(sender);
+        assert_eq!(Some(2), receiver.recv().await);
+        assert_eq!(None, receiver.recv().await);
+        assert_eq!(0, depth.get());
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::HashSet;
+use std::path::{ Path, PathBuf };
+use std::time::Duration;
+
+use serde::{ Serialize, Deserialize };
+
+use crate::model::r#match::MatchFileKey;
+use crate::model::summoner::Summoner;
+use crate::util::atomic_file::AtomicFile;
+
+const FILE_NAME: &'static str = "checkpoint.json";
+
+// Minimum time between checkpoint writes while scanning.
+pub const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+// Progress of an unfinished run, stored in `data/<region>/local` so a restarted run can resume.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Checkpoint {
+    // Summoners selected for the run, with scanned ones already updated.
+    pub summoners: Vec<Summoner>,
+    // Number of `summoners` whose matchlist has been scanned.
+    pub scanned: usize,
+    pub update_summoner_ts: u64,
+    // Match IDs marked as seen in the hybitset but not yet saved to a match file.
+    pub pending_match_ids: Vec<i64>,
+    // Patch dirs and ISO weeks of match files written to, whose stats may be behind their rows
+    // (or whose rows may still be pending) after a crash. See `stats::repair_stale_stats`.
+    #[serde(default)]
+    pub stale_stats: Vec<(String, String)>,
+}
+
+// Borrowed version of `Checkpoint`, for writing without cloning.
+#[derive(Serialize)]
+struct CheckpointRef<'a> {
+    summoners: &'a [Summoner],
+    scanned: usize,
+    update_summoner_ts: u64,
+    pending_match_ids: &'a [i64],
+    stale_stats: &'a [(String, String)],
+}
+
+fn path(path_data_local: &Path) -> PathBuf {
+    path_data_local.join(FILE_NAME)
+}
+
+pub fn read_checkpoint(path_data_local: impl AsRef<Path>) -> std::io::Result<Option<Checkpoint>> {
+    let path = path(path_data_local.as_ref());
+    if !path.exists() {
+        return Ok(None);
+    }
+    let bytes = std::fs::read(path)?;
+    let checkpoint = serde_json::from_slice(&bytes)?;
+    Ok(Some(checkpoint))
+}
+
+// Writes to a temp file and renames, so a crash never leaves a partial checkpoint.
+pub fn write_checkpoint(path_data_local: impl AsRef<Path>,
+    summoners: &[Summoner], scanned: usize, update_summoner_ts: u64, pending_match_ids: &[i64],
+    stale_stats: &[(String, String)])
+    -> std::io::Result<()>
+{
+    let path = path(path_data_local.as_ref());
+
+    let checkpoint = CheckpointRef {
+        summoners: summoners,
+        scanned: scanned,
+        update_summoner_ts: update_summoner_ts,
+        pending_match_ids: pending_match_ids,
+        stale_stats: stale_stats,
+    };
+    let bytes = serde_json::to_vec(&checkpoint)?;
+    let mut file = AtomicFile::create(path)?;
+    std::io::Write::write_all(&mut file, &bytes)?;
+    file.commit()
+}
+
+// Tracks pending matches while fetching, rewriting the checkpoint as matches are saved.
+pub struct PendingMatches {
+    path_data_local: PathBuf,
+    summoners: Vec<Summoner>,
+    update_summoner_ts: u64,
+    pending: HashSet<i64>,
+    stale_stats: Vec<(String, String)>,
+}
+
+impl PendingMatches {
+    pub fn new(path_data_local: PathBuf, summoners: Vec<Summoner>, update_summoner_ts: u64,
+        pending_match_ids: &[i64], stale_stats: Vec<(String, String)>) -> Self
+    {
+        Self {
+            path_data_local: path_data_local,
+            summoners: summoners,
+            update_summoner_ts: update_summoner_ts,
+            pending: pending_match_ids.iter().cloned().collect(),
+            stale_stats: stale_stats,
+        }
+    }
+
+    // Writes the checkpoint, with all summoners scanned.
+    pub fn write(&self) -> std::io::Result<()> {
+        let pending = self.pending.iter().cloned().collect::<Vec<_>>();
+        write_checkpoint(&self.path_data_local, &self.summoners, self.summoners.len(),
+            self.update_summoner_ts, &pending, &self.stale_stats)
+    }
+
+    // Marks the files of `match_keys` as stale, must be called before syncing any rows to them.
+    pub fn stale(&mut self, match_keys: impl Iterator<Item = MatchFileKey>) -> std::io::Result<()> {
+        let len = self.stale_stats.len();
+        for match_key in match_keys {
+            let stale = (match_key.patch_str(), match_key.iso_week_str());
+            if !self.stale_stats.contains(&stale) {
+                self.stale_stats.push(stale);
+            }
+        }
+        if len == self.stale_stats.len() {
+            return Ok(());
+        }
+        self.write()
+    }
+
+    pub fn saved(&mut self, match_ids: &[u64]) -> std::io::Result<()> {
+        if match_ids.is_empty() {
+            return Ok(());
+        }
+        for match_id in match_ids {
+            self.pending.remove(&(*match_id as i64));
+        }
+        self.write()
+    }
+}
+
+// Called once everything from the run is saved.
+pub fn remove_checkpoint(path_data_local: impl AsRef<Path>) -> std::io::Result<()> {
+    let path = path(path_data_local.as_ref());
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let dir = std::env::temp_dir().join("pbw_checkpoint_roundtrip");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let summoners = vec![ Summoner {
+            encrypted_summoner_id: "abc".to_owned(),
+            encrypted_account_id: Some("def".to_owned()),
+            league_id: None,
+            rank_tier: None,
+            rank_division: None,
+            league_points: None,
+            games_per_day: Some(2.5),
+            ts: Some(100),
+        } ];
+        let stale_stats = vec![ ("10.4".to_owned(), "2020-W09".to_owned()) ];
+        write_checkpoint(&dir, &summoners, 1, 200, &[ 5, 6 ], &stale_stats).unwrap();
+
+        let checkpoint = read_checkpoint(&dir).unwrap().unwrap();
+        assert_eq!(1, checkpoint.summoners.len());
+        assert_eq!(Some(2.5), checkpoint.summoners[0].games_per_day);
+        assert_eq!(1, checkpoint.scanned);
+        assert_eq!(200, checkpoint.update_summoner_ts);
+        assert_eq!(vec![ 5, 6 ], checkpoint.pending_match_ids);
+        assert_eq!(stale_stats, checkpoint.stale_stats);
+
+        remove_checkpoint(&dir).unwrap();
+        assert!(read_checkpoint(&dir).unwrap().is_none());
+    }
+}
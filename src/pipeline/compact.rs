@@ -0,0 +1,155 @@
+use std::collections::HashSet;
+use std::path::{ Path, PathBuf };
+
+use chrono::{ DateTime, Duration };
+use chrono::offset::Utc;
+
+use crate::util::atomic_file;
+use crate::util::csvgz;
+use crate::util::file_find;
+use crate::util::lol;
+use crate::util::time;
+
+// Which timestamped snapshots (`name.<datetimestamp>.ext`) to keep.
+#[derive(Clone, Copy, Debug)]
+pub struct Retention {
+    // Most recent snapshots always kept (at least one).
+    pub keep_last: usize,
+    // Older snapshots within this many days keep the newest per (UTC) day.
+    pub keep_daily_days: i64,
+}
+
+#[derive(Default, Debug)]
+pub struct CompactSummary {
+    pub snapshots_removed: usize,
+    pub match_files_rewritten: usize,
+    pub bytes_reclaimed: i64,
+}
+
+impl CompactSummary {
+    pub fn add(&mut self, other: &CompactSummary) {
+        self.snapshots_removed     += other.snapshots_removed;
+        self.match_files_rewritten += other.match_files_rewritten;
+        self.bytes_reclaimed       += other.bytes_reclaimed;
+    }
+}
+
+impl std::fmt::Display for CompactSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} snapshots removed, {} match files rewritten, {} bytes reclaimed.",
+            self.snapshots_removed, self.match_files_rewritten, self.bytes_reclaimed)
+    }
+}
+
+// Snapshots not kept by `retention`, given (timestamp, path) pairs in any order.
+pub fn select_expired(mut snapshots: Vec<(DateTime<Utc>, PathBuf)>, now: DateTime<Utc>, retention: Retention)
+    -> Vec<PathBuf>
+{
+    // Newest first.
+    snapshots.sort_by(|a, b| b.cmp(a));
+    let daily_cutoff = now - Duration::days(retention.keep_daily_days);
+
+    let mut days_kept = HashSet::new();
+    let mut expired = vec![];
+    for (i, (ts, path)) in snapshots.into_iter().enumerate() {
+        let keep = i < std::cmp::max(1, retention.keep_last)
+            || (daily_cutoff <= ts && days_kept.insert(ts.date()));
+        if keep {
+            days_kept.insert(ts.date());
+        } else {
+            expired.push(path);
+        }
+    }
+    expired
+}
+
+// Removes `name.*.ext` snapshots in `dir` not kept by `retention`.
+// Files whose timestamp doesn't parse are left alone.
+pub fn compact_snapshots(dir: impl AsRef<Path>, name: &str, ext: &str, now: DateTime<Utc>, retention: Retention)
+    -> std::io::Result<CompactSummary>
+{
+    let paths = file_find::find_all(&dir, name, ext).expect("Failed to find snapshots.");
+    let snapshots = paths.into_iter()
+        .filter_map(|path| {
+            let ts = file_find::get_infix(&path, name, ext)
+                .and_then(|infix| time::parse_datetimestamp(infix).ok())?;
+            Some((ts, path))
+        })
+        .collect();
+
+    let mut summary = CompactSummary::default();
+    for path in select_expired(snapshots, now, retention) {
+        summary.bytes_reclaimed += std::fs::metadata(&path)?.len() as i64;
+        std::fs::remove_file(&path)?;
+        summary.snapshots_removed += 1;
+    }
+    Ok(summary)
+}
+
+// Rewrites multi-member `matches.*.csv.gz` files in the `<major.minor>` dirs of `path_data` as single members.
+pub fn compact_match_files(path_data: impl AsRef<Path>) -> std::io::Result<CompactSummary> {
+    let mut summary = CompactSummary::default();
+    for entry in std::fs::read_dir(path_data)? {
+        let path_patch = entry?.path();
+        let is_patch = path_patch.is_dir() && path_patch.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(lol::parse_version)
+            .is_some();
+        if !is_patch {
+            continue;
+        }
+
+        let match_paths = file_find::find_all(&path_patch, "matches", "csv.gz")
+            .expect("Failed to find match files.");
+        for match_path in match_paths {
+            atomic_file::recover(&match_path)?;
+            if !csvgz::is_multi_member(&match_path)? {
+                continue;
+            }
+            let len_before = std::fs::metadata(&match_path)?.len() as i64;
+            csvgz::recompress(&match_path)?;
+            let len_after = std::fs::metadata(&match_path)?.len() as i64;
+
+            summary.bytes_reclaimed += len_before - len_after;
+            summary.match_files_rewritten += 1;
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_select_expired() {
+        let now = Utc.ymd(2020, 3, 31).and_hms(12, 0, 0);
+        let snapshots = vec![
+            // Two today, two yesterday, one a week ago, one two months ago.
+            Utc.ymd(2020, 3, 31).and_hms(11, 0, 0),
+            Utc.ymd(2020, 3, 31).and_hms(10, 0, 0),
+            Utc.ymd(2020, 3, 30).and_hms(22, 0, 0),
+            Utc.ymd(2020, 3, 30).and_hms(21, 0, 0),
+            Utc.ymd(2020, 3, 24).and_hms(9, 0, 0),
+            Utc.ymd(2020, 1, 31).and_hms(9, 0, 0),
+        ];
+        let snapshots = snapshots.into_iter()
+            .map(|ts| (ts, PathBuf::from(ts.to_rfc3339())))
+            .collect::<Vec<_>>();
+
+        let retention = Retention { keep_last: 1, keep_daily_days: 30 };
+        let expired = select_expired(snapshots.clone(), now, retention);
+        assert_eq!(vec![ snapshots[1].1.clone(), snapshots[3].1.clone(), snapshots[5].1.clone() ], expired);
+
+        let retention = Retention { keep_last: 3, keep_daily_days: 0 };
+        let expired = select_expired(snapshots.clone(), now, retention);
+        assert_eq!(vec![ snapshots[3].1.clone(), snapshots[4].1.clone(), snapshots[5].1.clone() ], expired);
+
+        // Latest always kept.
+        let retention = Retention { keep_last: 0, keep_daily_days: 0 };
+        let expired = select_expired(snapshots.clone(), now, retention);
+        assert_eq!(5, expired.len());
+        assert!(!expired.contains(&snapshots[0].1));
+    }
+}
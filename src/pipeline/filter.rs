@@ -1,4 +1,4 @@
-use std::collections::BinaryHeap;
+use std::collections::{ BinaryHeap, BTreeMap };
 use std::iter::IntoIterator;
 
 pub fn filter_min_n<I, T>(limit: usize, iter: I) -> BinaryHeap<T> where
@@ -8,19 +8,54 @@ pub fn filter_min_n<I, T>(limit: usize, iter: I) -> BinaryHeap<T> where
     let mut heap = BinaryHeap::with_capacity(limit);
 
     for item in iter {
-        // If we're full we'll need to pop.
-        if heap.len() >= limit {
-            // But if the item is already larger than the largest
-            // item in the heap, then we must ignore the item.
-            if item >= *heap.peek().unwrap() {
-                continue;
-            }
-            heap.pop();
+        push_min_n(&mut heap, limit, item);
+    }
+
+    heap
+}
+
+// Pushes `item` into `heap` of the `limit` smallest items, returning the item dropped (if any).
+fn push_min_n<T: Ord>(heap: &mut BinaryHeap<T>, limit: usize, item: T) -> Option<T> {
+    // If we're full we'll need to pop.
+    if heap.len() >= limit {
+        // But if the item is already larger than the largest
+        // item in the heap, then we must ignore the item.
+        if heap.peek().map(|largest| item >= *largest).unwrap_or(true) {
+            return Some(item);
         }
+        let out = heap.pop();
         heap.push(item);
+        return out;
     }
+    heap.push(item);
+    None
+}
 
-    heap
+// Like `filter_min_n`, but first reserves up to `quotas[key]` of the `limit` for each key's smallest items.
+// Items not kept for their key compete for the remaining slots.
+// Single pass, keeping at most `sum(quotas) + limit` items. `sum(quotas)` should not exceed `limit`.
+pub fn filter_min_n_stratified<I, K, T>(limit: usize, quotas: &BTreeMap<K, usize>, iter: I) -> Vec<T> where
+    I: IntoIterator<Item = (K, T)>,
+    K: Ord,
+    T: Ord,
+{
+    let mut heaps: BTreeMap<K, BinaryHeap<T>> = BTreeMap::new();
+    let mut overflow = BinaryHeap::with_capacity(limit);
+
+    for (key, item) in iter {
+        let quota = quotas.get(&key).cloned().unwrap_or(0);
+        let dropped = push_min_n(heaps.entry(key).or_insert_with(BinaryHeap::new), quota, item);
+        if let Some(dropped) = dropped {
+            push_min_n(&mut overflow, limit, dropped);
+        }
+    }
+
+    let mut out = heaps.into_iter()
+        .flat_map(|(_key, heap)| heap.into_vec())
+        .collect::<Vec<_>>();
+    let remaining = limit.saturating_sub(out.len());
+    out.extend(overflow.into_sorted_vec().into_iter().take(remaining));
+    out
 }
 
 #[cfg(test)]
@@ -35,4 +70,26 @@ mod test {
         let min_values = filter_min_n(5, values);
         println!("{:?}", min_values.into_iter().collect::<Vec<_>>());
     }
+
+    #[test]
+    fn test_filter_min_n_stratified() {
+        let mut quotas = BTreeMap::new();
+        quotas.insert('a', 1);
+        quotas.insert('b', 2);
+        // 'c' has no quota.
+        let values = vec![
+            ('a', 5), ('a', 1), ('a', 3),
+            ('b', 20), ('b', 40), ('b', 30),
+            ('c', 0), ('c', 2), ('c', 4) ];
+        let mut out = filter_min_n_stratified(5, &quotas, values);
+        out.sort();
+        // a: 1, b: 20 and 30, then the best of the rest: 0 and 2.
+        assert_eq!(vec![ 0, 1, 2, 20, 30 ], out);
+
+        // Unfilled quotas go to the rest.
+        let values = vec![ ('b', 20), ('c', 0), ('c', 2), ('c', 4), ('c', 6) ];
+        let mut out = filter_min_n_stratified(4, &quotas, values);
+        out.sort();
+        assert_eq!(vec![ 0, 2, 4, 20 ], out);
+    }
 }
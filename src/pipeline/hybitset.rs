@@ -1,25 +1,44 @@
-use std::path::{ Path };
+use std::path::{ Path, PathBuf };
 use std::error::Error;
+use std::io::Write;
 
-use tokio::fs::{ File, OpenOptions };
-use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
 
+use crate::util::atomic_file::AtomicFile;
 use crate::util::hybitset::HyBitSet;
 use crate::util::time;
 use crate::util::file_find;
 use crate::dyn_err;
 
-const FILE_TAG: &'static str = "match_hbs";
-const FILE_EXT: &'static str = "json";
+pub const FILE_TAG: &'static str = "match_hbs";
+pub const FILE_EXT: &'static str = "bin";
+// Legacy format, still read if newer than any binary file.
+pub const FILE_EXT_JSON: &'static str = "json";
+
+// Newest of the binary and JSON snapshots, with whether it is JSON.
+fn find_latest(path: &Path) -> Result<Option<(PathBuf, bool)>, glob::GlobError> {
+    let bin = file_find::find_latest(path, FILE_TAG, FILE_EXT)?;
+    let json = file_find::find_latest(path, FILE_TAG, FILE_EXT_JSON)?;
+    let ts = |path: &PathBuf, ext| file_find::get_infix(path, FILE_TAG, ext).map(str::to_owned);
+    Ok(match (bin, json) {
+        (Some(bin), Some(json)) => {
+            if ts(&json, FILE_EXT_JSON) > ts(&bin, FILE_EXT) { Some((json, true)) } else { Some((bin, false)) }
+        },
+        (Some(bin), None) => Some((bin, false)),
+        (None, Some(json)) => Some((json, true)),
+        (None, None) => None,
+    })
+}
 
 // TODO: really need to distinguish between "no files found" and "it fucked up".
 pub async fn read_match_hybitset(path: impl AsRef<Path>)
     -> Result<Option<HyBitSet>, Box<dyn Error + Send>>
 {
-    let path = match file_find::find_latest(path, FILE_TAG, FILE_EXT)
+    let (path, is_json) = match find_latest(path.as_ref())
         .map_err(dyn_err)?
     {
-        Some(path) => path,
+        Some(found) => found,
         None => return Ok(None),
     };
 
@@ -27,22 +46,28 @@ pub async fn read_match_hybitset(path: impl AsRef<Path>)
     let mut bytes = vec![];
     file.read_to_end(&mut bytes).await.map_err(dyn_err)?;
 
-    let hbs = serde_json::from_slice(&bytes).map_err(dyn_err)?;
-    Ok(hbs)
+    let hbs = if is_json {
+        serde_json::from_slice(&bytes).map_err(dyn_err)?
+    } else {
+        HyBitSet::from_bytes(&bytes).map_err(dyn_err)?
+    };
+    Ok(Some(hbs))
 }
 
+// Written in the binary format via `AtomicFile`.
 pub async fn write_match_hybitset(path: impl AsRef<Path>, match_hbs: &HyBitSet) -> Result<(), Box<dyn Error>> {
-    
     let path = path.as_ref().join(format!("{}.{}.{}", FILE_TAG, time::datetimestamp(), FILE_EXT));
+    if path.exists() {
+        return Err(Box::new(std::io::Error::new(std::io::ErrorKind::AlreadyExists,
+            format!("{:?} already exists.", path))));
+    }
 
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create_new(true)
-        .open(path).await?;
-
-    let bytes = serde_json::ser::to_vec_pretty(match_hbs)?;
-    file.write_all(&bytes).await?;
-    file.shutdown().await?;
+    let bytes = match_hbs.to_bytes(true);
+    tokio::task::spawn_blocking(move || -> std::io::Result<()> {
+        let mut file = AtomicFile::create(path)?;
+        file.write_all(&bytes)?;
+        file.commit()
+    }).await??;
 
     Ok(())
 }
@@ -1,4 +1,5 @@
 use std::cmp;
+use std::collections::HashSet;
 
 use chrono::DateTime;
 use chrono::offset::Utc;
@@ -6,18 +7,21 @@ use futures::future::join_all;
 use itertools::Itertools;
 use riven::consts::{ Region, Queue };
 use riven::models::match_v4::Match;
-use riven::RiotApi;
 use tokio::sync::mpsc;
 
+use crate::api::{ Api, ApiResult };
 use crate::model::summoner::Summoner;
+use crate::pipeline::channel;
+use crate::pipeline::watermark::MatchWatermark;
 use crate::util::hybitset::HyBitSet;
+use crate::util::shutdown;
 
 
 const MILLIS_PER_DAY: usize = 24 * 3600 * 1000;
 
 
 pub async fn update_missing_summoner_account_ids(
-    api: &RiotApi, region: Region, chunk_size: usize, mut summoners: Vec<Summoner>) -> Vec<Summoner>
+    api: &dyn Api, region: Region, chunk_size: usize, mut summoners: Vec<Summoner>) -> Vec<Summoner>
 {
     // Summoners without AccountIDs (AID).
     for summoner_chunk in summoners.iter_mut()
@@ -25,11 +29,14 @@ pub async fn update_missing_summoner_account_ids(
         .chunks(chunk_size)
         .into_iter()
     {
+        if shutdown::requested() {
+            break;
+        }
         let summoner_chunk = summoner_chunk.collect::<Vec<_>>();
 
         let summoner_datas = summoner_chunk.iter()
             .map(|summoner| &summoner.encrypted_summoner_id)
-            .map(|sid| api.summoner_v4().get_by_summoner_id(region, sid))
+            .map(|sid| api.get_summoner(region, sid))
             .collect::<Vec<_>>();
         let summoner_datas = join_all(summoner_datas).await;
 
@@ -42,30 +49,41 @@ pub async fn update_missing_summoner_account_ids(
     summoners
 }
 
-pub async fn get_new_matchids_update_summoner_gpd(
-    api: &RiotApi, region: Region, queue: Queue,
-    batch_size: usize, starttime: DateTime<Utc>,
-    oldest_summoners: &mut Vec<Summoner>, match_hbs: &mut HyBitSet)
+// Scans matchlists of `summoners[scanned..]`, updating their games per day and returning new match IDs.
+// `on_progress(summoners, scanned, new_match_ids)` is called after each batch, for checkpointing.
+// Summoners whose matchlist failed are removed from `summoners` after each batch, so they aren't marked as updated.
+// On shutdown, stops early and also removes the summoners not yet scanned.
+pub async fn get_new_matchids_update_summoner_gpd<F>(
+    api: &dyn Api, region: Region, queue: Queue,
+    batch_size: usize, now: DateTime<Utc>, starttime: DateTime<Utc>,
+    summoners: &mut Vec<Summoner>, mut scanned: usize, match_hbs: &mut HyBitSet,
+    watermark: &mut MatchWatermark, mut on_progress: F)
     -> Vec<i64>
+where
+    F: FnMut(&[Summoner], usize, &[i64]),
 {
-    let now_millis = Utc::now().timestamp_millis();
+    let now_millis = now.timestamp_millis();
+    let mut failed = HashSet::new();
     // Chunk size? Shitty parallelism?
     let mut new_matches = vec![];
-    for summoners_chunk in oldest_summoners.chunks_mut(batch_size) {
+    while scanned < summoners.len() {
+        if shutdown::requested() {
+            println!("Shutdown requested, stopping after {} of {} summoners.", scanned, summoners.len());
+            summoners.truncate(scanned);
+            break;
+        }
+        let chunk_end = cmp::min(summoners.len(), scanned + batch_size);
+        let summoners_chunk = &mut summoners[scanned..chunk_end];
+
         let chunk_futures = summoners_chunk.iter()
             .filter(|summoner| summoner.encrypted_account_id.is_some())
             .map(|summoner| {
                 let begin_millis = cmp::max(starttime.timestamp_millis(), summoner.ts.unwrap_or(0) as i64);
-                let matches_dto = api.match_v4().get_matchlist(
+                let matches_dto = api.get_matchlist(
                     region,
                     summoner.encrypted_account_id.as_ref().unwrap(),
-                    Some(begin_millis), // begin_time
-                    None, // begin_index
-                    None, // champion
-                    None, // end_time
-                    None, // end_index
-                    Some(vec![ queue ]), // queue
-                    None, // season
+                    begin_millis,
+                    queue,
                 );
                 matches_dto
             }).collect::<Vec<_>>();
@@ -73,60 +91,103 @@ pub async fn get_new_matchids_update_summoner_gpd(
         let list_of_lists_of_matches = join_all(chunk_futures).await;
 
         let lists_of_match_ids = list_of_lists_of_matches.into_iter()
-            .zip(summoners_chunk.iter_mut())
-            .flat_map(|(m, summoner): (riven::Result<Option<riven::models::match_v4::Matchlist>>, &mut Summoner)| {
-                let matchlist_opt = m.expect("Failed to get matchlist");
+            .zip(summoners_chunk.iter_mut().filter(|summoner| summoner.encrypted_account_id.is_some()))
+            .flat_map(|(m, summoner): (ApiResult<Option<riven::models::match_v4::Matchlist>>, &mut Summoner)| {
+                let matchlist_opt = match m {
+                    Ok(matchlist_opt) => matchlist_opt,
+                    Err(e) => {
+                        println!("Failed to get matchlist for {}: {}.", summoner.encrypted_summoner_id, e);
+                        failed.insert(summoner.encrypted_summoner_id.clone());
+                        None
+                    },
+                };
                 match matchlist_opt {
                     Some(matchlist) => {
                         // TODO: duplicate begin_time for each summoner.
                         // Code here updates games_per_day.
                         let begin_millis = cmp::max(starttime.timestamp_millis(), summoner.ts.unwrap_or(0) as i64);
                         let delta_millis = now_millis - begin_millis;
-                        let new_games_per_day = ((matchlist.matches.len() * MILLIS_PER_DAY) as f32) / (delta_millis as f32);
-                        let old_games_per_day = summoner.games_per_day.unwrap_or(new_games_per_day);
-                        summoner.games_per_day = Some((old_games_per_day + new_games_per_day) / 2.0);
+                        // An empty window says nothing (and would divide by zero).
+                        if 0 < delta_millis {
+                            let new_games_per_day = ((matchlist.matches.len() * MILLIS_PER_DAY) as f32) / (delta_millis as f32);
+                            let old_games_per_day = summoner.games_per_day
+                                .filter(|games_per_day| games_per_day.is_finite())
+                                .unwrap_or(new_games_per_day);
+                            summoner.games_per_day = Some((old_games_per_day + new_games_per_day) / 2.0);
+                        }
                         // Return matchlist.
                         matchlist.matches
                     },
                     None => vec![],
                 }
             })
-            .map(|matche| matche.game_id);
+            .map(|matche| {
+                watermark.observe(matche.game_id as usize, matche.timestamp);
+                matche.game_id
+            });
         for match_id in lists_of_match_ids {
             // Insert into bitmap. If match was not in bitmap, then add it to new_matches.
             if !match_hbs.insert(match_id as usize) {
                 new_matches.push(match_id);
             }
         }
+
+        // Failed summoners are dropped right away, so they are never counted as scanned.
+        let kept = summoners.drain(scanned..chunk_end)
+            .filter(|summoner| !failed.contains(&summoner.encrypted_summoner_id))
+            .collect::<Vec<_>>();
+        let kept_len = kept.len();
+        summoners.splice(scanned..scanned, kept);
+        scanned += kept_len;
+        failed.clear();
+        on_progress(summoners, scanned, &new_matches);
     }
     new_matches
 }
 
-pub async fn get_matches_mpsc(sender: mpsc::UnboundedSender<Match>,
-    api: &RiotApi, region: Region, chunk_size: usize, match_ids: Vec<i64>)
-    -> Result<usize, mpsc::error::SendError<Match>>
+// Fetches matches into `sender`, waiting when it is full.
+// `depths` are the queue depths of the downstream channels, for progress output.
+// On shutdown, stops before the next chunk, leaving the rest pending in the checkpoint.
+// Returns the number fetched and the IDs of matches which failed, which stay pending for a retry.
+pub async fn get_matches_mpsc(mut sender: channel::Sender<Match>, depths: Vec<channel::Depth>,
+    api: &dyn Api, region: Region, chunk_size: usize, match_ids: Vec<i64>)
+    -> Result<(usize, Vec<i64>), mpsc::error::SendError<Match>>
 {
     let mut count = 0;
-    for match_ids_chunk in match_ids.chunks(chunk_size) {
+    let mut failed = vec![];
+    for (i, match_ids_chunk) in match_ids.chunks(chunk_size).enumerate() {
+        if shutdown::requested() {
+            println!("Shutdown requested, leaving {} matches unfetched.", match_ids.len() - i * chunk_size);
+            break;
+        }
 
         let chunk_futures = match_ids_chunk.into_iter()
-            .map(|match_id| api.match_v4().get_match(region, *match_id))
+            .map(|match_id| api.get_match(region, *match_id))
             .collect::<Vec<_>>();
 
         let matches = join_all(chunk_futures).await;
-        let matches = matches.into_iter()
-            .filter_map(|m| m.ok()) // Remove errors (TODO: silent).
-            .filter_map(|m| m); // Remove 404 (TODO: silent).
+        let mut fetched = vec![];
+        for (match_id, matche) in match_ids_chunk.iter().zip(matches) {
+            match matche {
+                Ok(Some(matche)) => fetched.push(matche),
+                Ok(None) => (), // Remove 404 (TODO: silent).
+                Err(e) => {
+                    println!("Failed to get match {}: {}.", match_id, e);
+                    failed.push(*match_id);
+                },
+            }
+        }
 
-        for matche in matches {
-            sender.send(matche)?;
+        for matche in fetched {
+            sender.send(matche).await?;
             count += 1;
             if 0 == count % 10_000 {
-                println!("  Fetched {} matches so far.", count);
+                let depths = depths.iter().map(|depth| depth.get().to_string()).collect::<Vec<_>>();
+                println!("  Fetched {} matches so far, queue depths: {}.", count, depths.join("/"));
             }
         }
     }
-    Ok(count)
+    Ok((count, failed))
 }
 
 // pub async fn get_matches(
@@ -0,0 +1,75 @@
+use std::collections::{ HashMap, HashSet };
+use std::path::PathBuf;
+
+use crate::model::r#match::{ Match, MatchFileKey };
+use crate::pipeline::source_fs;
+use crate::util::csvgz;
+
+// Writes matches to their `<major.minor>/matches.<iso_week>.csv.gz` file as they arrive,
+// keeping one open writer per file key for the whole run.
+// Blocking, so used from a blocking task, see `match_stages::write_stage`.
+pub struct MatchSink {
+    path_data: PathBuf,
+    writers: HashMap<MatchFileKey, csvgz::Writer>,
+    // Writers written to since the last flush.
+    dirty: HashSet<MatchFileKey>,
+    // Paths of all files written to.
+    written: HashMap<MatchFileKey, PathBuf>,
+}
+
+impl MatchSink {
+    pub fn new(path_data: PathBuf) -> Self {
+        Self {
+            path_data: path_data,
+            writers: HashMap::new(),
+            dirty: HashSet::new(),
+            written: HashMap::new(),
+        }
+    }
+
+    // Patch directory of a file key, holding its match and stats files.
+    pub fn path_patch(&self, match_key: MatchFileKey) -> PathBuf {
+        self.path_data.join(match_key.patch_str())
+    }
+
+    pub fn write(&mut self, match_key: MatchFileKey, matche: &Match) -> std::io::Result<()> {
+        if !self.writers.contains_key(&match_key) {
+            let path_patch = self.path_patch(match_key);
+            let iso_week_str = match_key.iso_week_str();
+
+            // Create directory (if not exists) for this patch.
+            std::fs::create_dir_all(&path_patch)?;
+
+            let writer = source_fs::match_writer(&path_patch, &iso_week_str)?;
+            self.writers.insert(match_key, writer);
+            self.written.insert(match_key, source_fs::match_path(&path_patch, &iso_week_str));
+        }
+
+        self.writers.get_mut(&match_key).unwrap().serialize(matche)?;
+        self.dirty.insert(match_key);
+        Ok(())
+    }
+
+    // Makes rows written since the last flush durable.
+    // Writers stay open, each flush ends a gzip member in their files.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        for match_key in self.dirty.drain() {
+            let writer = self.writers.remove(&match_key).unwrap();
+            self.writers.insert(match_key, csvgz::flush(writer)?);
+        }
+        Ok(())
+    }
+
+    // Flushes and closes all writers, then recompresses each file written as a single gzip member.
+    // Returns the number of files written.
+    pub fn finish(mut self) -> std::io::Result<usize> {
+        self.flush()?;
+        for (_, writer) in self.writers.drain() {
+            csvgz::finish(writer)?;
+        }
+        for path in self.written.values() {
+            csvgz::recompress(path)?;
+        }
+        Ok(self.written.len())
+    }
+}
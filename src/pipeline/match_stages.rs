@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+
+use riven::consts::Region;
+use riven::models::match_v4;
+use tokio::task;
+
+use crate::dyn_err;
+use crate::model::r#match::{ Match, MatchFileKey };
+use crate::model::rank::Rank;
+use crate::pipeline::channel::{ Receiver, Sender };
+use crate::pipeline::checkpoint::PendingMatches;
+use crate::pipeline::match_sink::MatchSink;
+use crate::pipeline::rank_history::RankHistory;
+use crate::pipeline::stats::{ self, Stats };
+use crate::util::lol;
+
+// Consumer stages for fetched matches, connected by bounded channels:
+// fetch -> `rank_stage` -> `stats_stage` -> `write_stage`.
+
+// Matches per batch, each batch is made durable in one flush.
+pub const BATCH_SIZE: usize = 1_000;
+
+// Matches passed from `stats_stage` to `write_stage`, with their stats.
+pub struct MatchBatch {
+    pub matches: Vec<(MatchFileKey, Match)>,
+    pub stats: Stats,
+}
+
+// Assigns each match its average rank and converts it to the stored model.
+// Participants' ranks are as of the match from `rank_history` if known, otherwise their current rank.
+// Returns participants' summoner IDs mapped to their account IDs, for discovering new summoners.
+// Only participants currently on `region`'s platform, others' IDs belong to another region.
+pub async fn rank_stage(mut receiver: Receiver<match_v4::Match>, mut sender: Sender<(MatchFileKey, Match)>,
+    region: Region, ranked_summoners: Arc<HashMap<String, Rank>>, rank_history: Arc<RankHistory>)
+    -> Result<HashMap<String, String>, Box<dyn Error + Send>>
+{
+    let platform_id = region.to_string();
+    let mut participants = HashMap::new();
+    while let Some(matche) = receiver.recv().await {
+        let match_key = MatchFileKey::from(&matche);
+
+        for participant in matche.participant_identities.iter() {
+            let player = &participant.player;
+            let on_platform = player.current_platform_id.eq_ignore_ascii_case(&platform_id);
+            if on_platform && !player.summoner_id.is_empty() && !player.current_account_id.is_empty() {
+                participants.insert(player.summoner_id.clone(), player.current_account_id.clone());
+            }
+        }
+
+        let scores = matche.participant_identities.iter()
+            .map(|participant| {
+                let summoner_id = &participant.player.summoner_id;
+                rank_history.rank_at(summoner_id, matche.game_creation as u64)
+                    .map(|change| lol::rank_score(change.tier, change.division, change.league_points))
+                    .or_else(|| ranked_summoners.get(summoner_id)
+                        .map(|rank| lol::rank_score(rank.tier, rank.division, rank.league_points)))
+            });
+        let avg_score = lol::match_avg_rank(scores);
+        let model_match = Match::from_api(&matche, avg_score);
+
+        sender.send((match_key, model_match)).await.map_err(dyn_err)?;
+    }
+    Ok(participants)
+}
+
+// Groups matches into batches and accumulates each batch's stats by file key.
+pub async fn stats_stage(mut receiver: Receiver<(MatchFileKey, Match)>, mut sender: Sender<MatchBatch>)
+    -> Result<(), Box<dyn Error + Send>>
+{
+    let mut batch = MatchBatch { matches: Vec::with_capacity(BATCH_SIZE), stats: Stats::new() };
+    while let Some((match_key, model_match)) = receiver.recv().await {
+        batch.stats.add_match(match_key, &model_match);
+        batch.matches.push((match_key, model_match));
+
+        if BATCH_SIZE <= batch.matches.len() {
+            let full = std::mem::replace(&mut batch,
+                MatchBatch { matches: Vec::with_capacity(BATCH_SIZE), stats: Stats::new() });
+            sender.send(full).await.map_err(dyn_err)?;
+        }
+    }
+    if !batch.matches.is_empty() {
+        sender.send(batch).await.map_err(dyn_err)?;
+    }
+    Ok(())
+}
+
+// Writes each batch of matches and their stats to their files, then removes them from the checkpoint.
+// File I/O runs on blocking tasks. Returns the number of match files written.
+pub async fn write_stage(mut receiver: Receiver<MatchBatch>,
+    mut match_sink: MatchSink, mut pending_matches: PendingMatches)
+    -> Result<usize, Box<dyn Error + Send>>
+{
+    while let Some(batch) = receiver.recv().await {
+        let written = task::spawn_blocking(move || -> std::io::Result<(MatchSink, PendingMatches)> {
+            write_batch(&mut match_sink, &mut pending_matches, batch)?;
+            Ok((match_sink, pending_matches))
+        }).await.map_err(dyn_err)?.map_err(dyn_err)?;
+        match_sink = written.0;
+        pending_matches = written.1;
+    }
+    task::spawn_blocking(move || match_sink.finish())
+        .await.map_err(dyn_err)?.map_err(dyn_err)
+}
+
+// Each step is durable before the next, so a crash at any point is repaired on resume:
+// files are marked stale before their rows are synced, their stats updated after,
+// and the matches are only then no longer pending. See `stats::repair_stale_stats`.
+fn write_batch(match_sink: &mut MatchSink, pending_matches: &mut PendingMatches, batch: MatchBatch)
+    -> std::io::Result<()>
+{
+    pending_matches.stale(batch.stats.tables.keys().cloned())?;
+    for (match_key, model_match) in batch.matches.iter() {
+        match_sink.write(*match_key, model_match)?;
+    }
+    match_sink.flush()?;
+    for (match_key, stats_table) in batch.stats.tables {
+        stats::update_stats_file(&match_sink.path_patch(match_key), &match_key.iso_week_str(), stats_table)?;
+    }
+    let match_ids = batch.matches.iter().map(|(_, model_match)| model_match.match_id).collect::<Vec<_>>();
+    pending_matches.saved(&match_ids)
+}
@@ -1,6 +1,15 @@
 pub mod basic;
+pub mod channel;
+pub mod checkpoint;
+pub mod compact;
 pub mod filter;
 pub mod mapping_api;
 pub mod hybitset;
+pub mod match_sink;
+pub mod match_stages;
+pub mod rank_history;
+pub mod selection;
 pub mod source_api;
 pub mod source_fs;
+pub mod stats;
+pub mod watermark;
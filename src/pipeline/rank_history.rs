@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::path::{ Path, PathBuf };
+
+use crate::model::rank::{ Rank, RankChange };
+use crate::util::csvgz;
+
+const FILE_NAME: &'static str = "rank_history.csv.gz";
+
+// Rank transitions per summoner, from `local/rank_history.csv.gz`.
+// Only league pulls add to it, so ranks between pulls are approximate.
+#[derive(Default, Debug)]
+pub struct RankHistory {
+    // Changes sorted by time.
+    by_summoner: HashMap<String, Vec<RankChange>>,
+}
+
+impl RankHistory {
+    fn add(&mut self, change: RankChange) {
+        let changes = self.by_summoner.entry(change.summoner_id.clone()).or_insert_with(Vec::new);
+        let i = changes.iter().rposition(|other| other.ts <= change.ts).map(|i| i + 1).unwrap_or(0);
+        changes.insert(i, change);
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.by_summoner.len()
+    }
+
+    // Records the ranks of a pull at `ts`, returning those which changed (or are new).
+    // Summoners missing from the pull are left as is, since pages can fail.
+    pub fn record_pull<'a>(&mut self, ranks: impl Iterator<Item = (&'a String, &'a Rank)>, ts: u64) -> Vec<RankChange> {
+        let mut changes = vec![];
+        for (summoner_id, rank) in ranks {
+            let unchanged = self.by_summoner.get(summoner_id)
+                .and_then(|changes| changes.last())
+                .map(|last| last.same_rank(rank))
+                .unwrap_or(false);
+            if !unchanged {
+                let change = RankChange::new(summoner_id.clone(), ts, rank);
+                self.add(change.clone());
+                changes.push(change);
+            }
+        }
+        changes
+    }
+
+    // The summoner's rank as of `ts` (epoch millis), if recorded by then.
+    pub fn rank_at(&self, summoner_id: &str, ts: u64) -> Option<&RankChange> {
+        let changes = self.by_summoner.get(summoner_id)?;
+        let i = changes.iter().rposition(|change| change.ts <= ts)?;
+        Some(&changes[i])
+    }
+}
+
+fn path(path_data_local: &Path) -> PathBuf {
+    path_data_local.join(FILE_NAME)
+}
+
+pub fn read_rank_history(path_data_local: impl AsRef<Path>) -> std::io::Result<RankHistory> {
+    let path = path(path_data_local.as_ref());
+    let mut history = RankHistory::default();
+    if !path.exists() {
+        return Ok(history);
+    }
+    for change in csvgz::reader(path)?.into_deserialize() {
+        history.add(change?);
+    }
+    Ok(history)
+}
+
+pub fn append_rank_history(path_data_local: impl AsRef<Path>, changes: &[RankChange]) -> std::io::Result<()> {
+    if changes.is_empty() {
+        return Ok(());
+    }
+    let mut writer = csvgz::appender(path(path_data_local.as_ref()))?;
+    for change in changes {
+        writer.serialize(change)?;
+    }
+    csvgz::finish(writer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use riven::consts::{ Division, Tier };
+
+    fn rank(tier: Tier, division: Division, league_points: i32) -> Rank {
+        Rank {
+            tier: tier,
+            division: Some(division),
+            league_points: Some(league_points),
+            league_id: "league".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_rank_at() {
+        let a = "a".to_owned();
+        let mut history = RankHistory::default();
+
+        let pull = vec![ (a.clone(), rank(Tier::GOLD, Division::II, 50)) ];
+        assert_eq!(1, history.record_pull(pull.iter().map(|(id, rank)| (id, rank)), 1000).len());
+        // Unchanged.
+        assert_eq!(0, history.record_pull(pull.iter().map(|(id, rank)| (id, rank)), 2000).len());
+        let pull = vec![ (a.clone(), rank(Tier::PLATINUM, Division::IV, 0)) ];
+        assert_eq!(1, history.record_pull(pull.iter().map(|(id, rank)| (id, rank)), 3000).len());
+
+        assert!(history.rank_at("a", 999).is_none());
+        assert_eq!(Tier::GOLD, history.rank_at("a", 1000).unwrap().tier);
+        assert_eq!(Tier::GOLD, history.rank_at("a", 2999).unwrap().tier);
+        assert_eq!(Tier::PLATINUM, history.rank_at("a", 3000).unwrap().tier);
+        assert!(history.rank_at("b", 3000).is_none());
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let dir = std::env::temp_dir().join("pbw_rank_history");
+        std::fs::create_dir_all(&dir).unwrap();
+        let _ = std::fs::remove_file(path(&dir));
+
+        let a = "a".to_owned();
+        let mut history = RankHistory::default();
+        let changes = history.record_pull(std::iter::once((&a, &rank(Tier::GOLD, Division::II, 50))), 1000);
+        append_rank_history(&dir, &changes).unwrap();
+        let changes = history.record_pull(std::iter::once((&a, &rank(Tier::GOLD, Division::I, 10))), 2000);
+        append_rank_history(&dir, &changes).unwrap();
+
+        let history = read_rank_history(&dir).unwrap();
+        assert_eq!(Some(Division::II), history.rank_at("a", 1500).unwrap().division);
+        assert_eq!(Some(10), history.rank_at("a", 2500).unwrap().league_points);
+    }
+}
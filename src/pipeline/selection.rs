@@ -0,0 +1,237 @@
+use std::collections::BTreeMap;
+
+use chrono::{ DateTime, Duration };
+use chrono::offset::Utc;
+use riven::consts::{ IntoEnumIterator, Tier };
+
+use crate::model::summoner::{ Summoner, SummonerOldest, SummonerYield };
+use crate::util::error::PbwError;
+use super::filter;
+
+// Games per day assumed for summoners without a valid estimate.
+const DEFAULT_GAMES_PER_DAY: f32 = 1.0;
+// Lowest games per day used for yield, so inactive summoners' estimates can still recover.
+const MIN_GAMES_PER_DAY: f32 = 0.1;
+// Summoners unscanned this long are picked first by yield regardless.
+const MAX_UNSCANNED_DAYS: i64 = 30;
+
+// How summoners are picked for matchlist scanning each run.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Selection {
+    // Least recently scanned first.
+    Oldest,
+    // Highest expected number of unseen matches first.
+    Yield,
+}
+
+impl Selection {
+    pub const NAMES: [&'static str; 2] = [ "oldest", "yield" ];
+
+    pub fn parse(name: &str) -> Result<Selection, PbwError> {
+        match name {
+            "oldest" => Ok(Selection::Oldest),
+            "yield" => Ok(Selection::Yield),
+            _ => Err(PbwError::new(format!("Unknown selection: {}, expected one of: {}.",
+                name, Self::NAMES.join(", ")))),
+        }
+    }
+
+    // Picks up to `update_size` summoners.
+    // With `tier_weights`, each tier first gets its share of `update_size`, see `TierWeights`.
+    pub fn select(self, update_size: usize, summoners: impl Iterator<Item = Summoner>,
+        now: DateTime<Utc>, lookbehind: Duration, tier_weights: &TierWeights)
+        -> Vec<Summoner>
+    {
+        fn pick<T: Ord>(update_size: usize, tier_weights: &TierWeights, items: impl Iterator<Item = (Option<Tier>, T)>)
+            -> Vec<T>
+        {
+            if tier_weights.is_empty() {
+                filter::filter_min_n(update_size, items.map(|(_tier, item)| item)).into_vec()
+            }
+            else {
+                filter::filter_min_n_stratified(update_size, &tier_weights.quotas(update_size), items)
+            }
+        }
+
+        match self {
+            Selection::Oldest => {
+                let summoners = summoners
+                    .map(|summoner| (summoner.rank_tier, SummonerOldest(summoner)));
+                pick(update_size, tier_weights, summoners)
+                    .into_iter().map(|s| s.0).collect()
+            },
+            Selection::Yield => {
+                let summoners = summoners
+                    .map(|summoner| (summoner.rank_tier,
+                        SummonerYield(expected_yield(&summoner, now, lookbehind), summoner)));
+                pick(update_size, tier_weights, summoners)
+                    .into_iter().map(|s| s.1).collect()
+            },
+        }
+    }
+}
+
+// Relative weights per tier for stratified selection, e.g. `MASTER=4,GRANDMASTER=4,CHALLENGER=4`.
+// Unlisted tiers weigh 1, `UNRANKED` is summoners without a tier. Empty means no stratification.
+// Each tier is reserved `update_size * weight / total weight` summoners, unfilled slots go to the best of the rest.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TierWeights(Vec<(Option<Tier>, f32)>);
+
+impl TierWeights {
+    pub fn parse(weights_str: &str) -> Result<TierWeights, PbwError> {
+        let mut weights = vec![];
+        for entry in weights_str.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let err = || PbwError::new(format!("Invalid tier weight: {}, expected TIER=weight.", entry));
+            let mut split = entry.splitn(2, '=');
+            let tier_str = split.next().ok_or_else(err)?.trim();
+            let weight: f32 = split.next().ok_or_else(err)?.trim().parse().map_err(|_e| err())?;
+            // Infinite weights would make every quota 0.
+            if weight.is_nan() || weight.is_infinite() || weight < 0.0 {
+                return Err(err());
+            }
+            let tier = if tier_str.eq_ignore_ascii_case("UNRANKED") {
+                None
+            } else {
+                Some(Tier::iter()
+                    .find(|tier| tier.to_string().eq_ignore_ascii_case(tier_str))
+                    .ok_or_else(|| PbwError::new(format!("Unknown tier: {}.", tier_str)))?)
+            };
+            weights.push((tier, weight));
+        }
+        Ok(TierWeights(weights))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn weight(&self, tier: Option<Tier>) -> f32 {
+        self.0.iter().rev()
+            .find(|(t, _weight)| tier == *t)
+            .map(|(_tier, weight)| *weight)
+            .unwrap_or(1.0)
+    }
+
+    // Summoners reserved per tier, summing to at most `update_size`.
+    pub fn quotas(&self, update_size: usize) -> BTreeMap<Option<Tier>, usize> {
+        let tiers = std::iter::once(None).chain(Tier::iter().map(Some)).collect::<Vec<_>>();
+        let total: f32 = tiers.iter().map(|tier| self.weight(*tier)).sum();
+        tiers.into_iter()
+            .map(|tier| {
+                let quota = if 0.0 < total { (update_size as f32) * self.weight(tier) / total } else { 0.0 };
+                (tier, quota.floor() as usize)
+            })
+            .collect()
+    }
+}
+
+// Expected unseen matches: games per day (at least `MIN_GAMES_PER_DAY`) times days since last scanned.
+// Matchlists only go back `lookbehind`, so time beyond that doesn't count.
+// Summoners never scanned or unscanned for `MAX_UNSCANNED_DAYS` get infinite yield, so none are starved.
+// Never NaN, so yields are totally ordered.
+pub fn expected_yield(summoner: &Summoner, now: DateTime<Utc>, lookbehind: Duration) -> f32 {
+    let elapsed_millis = match summoner.ts {
+        Some(ts) => now.timestamp_millis() - ts as i64,
+        None => return f32::INFINITY,
+    };
+    if Duration::days(MAX_UNSCANNED_DAYS).num_milliseconds() <= elapsed_millis {
+        return f32::INFINITY;
+    }
+    let elapsed_millis = std::cmp::min(lookbehind.num_milliseconds(), std::cmp::max(0, elapsed_millis));
+    let elapsed_days = (elapsed_millis as f32) / (Duration::days(1).num_milliseconds() as f32);
+    let games_per_day = summoner.games_per_day
+        .filter(|games_per_day| games_per_day.is_finite())
+        .unwrap_or(DEFAULT_GAMES_PER_DAY)
+        .max(MIN_GAMES_PER_DAY);
+    games_per_day * elapsed_days
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn summoner(id: &str, games_per_day: Option<f32>, ts: Option<DateTime<Utc>>) -> Summoner {
+        Summoner {
+            encrypted_summoner_id: id.to_owned(),
+            encrypted_account_id: None,
+            league_id: None,
+            rank_tier: None,
+            rank_division: None,
+            league_points: None,
+            games_per_day: games_per_day,
+            ts: ts.map(|ts| ts.timestamp_millis() as u64),
+        }
+    }
+
+    #[test]
+    fn test_select() {
+        let now = Utc.ymd(2020, 3, 31).and_hms(0, 0, 0);
+        let lookbehind = Duration::days(7);
+        let summoners = vec![
+            // Inactive, scanned a while ago: yield at the minimum games per day, 0.7.
+            summoner("inactive", Some(0.0), Some(now - Duration::days(20))),
+            // Active, scanned a day ago: yield 10.
+            summoner("active", Some(10.0), Some(now - Duration::days(1))),
+            // Moderate, scanned a while ago, capped at lookbehind: yield 14.
+            summoner("moderate", Some(2.0), Some(now - Duration::days(20))),
+            // Never scanned: infinite yield.
+            summoner("new", None, None),
+        ];
+        assert!((0.7 - expected_yield(&summoners[0], now, lookbehind)).abs() < 1e-4);
+        assert_eq!(14.0, expected_yield(&summoners[2], now, lookbehind));
+        assert_eq!(f32::INFINITY, expected_yield(&summoners[3], now, lookbehind));
+        // Bad estimates, e.g. from a zero-length scan window, use the default.
+        let mut bad = summoners[1].clone();
+        bad.games_per_day = Some(f32::NAN);
+        assert!((1.0 - expected_yield(&bad, now, lookbehind)).abs() < 1e-4);
+        bad.games_per_day = Some(f32::INFINITY);
+        bad.ts = Some(now.timestamp_millis() as u64);
+        assert_eq!(0.0, expected_yield(&bad, now, lookbehind));
+
+        let ids = |selected: Vec<Summoner>| {
+            let mut ids = selected.into_iter().map(|s| s.encrypted_summoner_id).collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+        let no_weights = TierWeights::default();
+        let selected = Selection::Yield.select(2, summoners.clone().into_iter(), now, lookbehind, &no_weights);
+        assert_eq!(vec![ "moderate", "new" ], ids(selected));
+        let selected = Selection::Oldest.select(2, summoners.clone().into_iter(), now, lookbehind, &no_weights);
+        assert_eq!(vec![ "inactive", "new" ], ids(selected));
+
+        // Unscanned too long, picked first.
+        let mut stale = summoners.clone();
+        stale[0].ts = Some((now - Duration::days(MAX_UNSCANNED_DAYS)).timestamp_millis() as u64);
+        assert_eq!(f32::INFINITY, expected_yield(&stale[0], now, lookbehind));
+        let selected = Selection::Yield.select(2, stale.into_iter(), now, lookbehind, &no_weights);
+        assert_eq!(vec![ "inactive", "new" ], ids(selected));
+
+        // Reserve for MASTER, taking the inactive master over higher yields.
+        let mut summoners = summoners;
+        summoners[0].rank_tier = Some(Tier::MASTER);
+        let weights = TierWeights::parse("master=1000").unwrap();
+        let selected = Selection::Yield.select(2, summoners.into_iter(), now, lookbehind, &weights);
+        assert_eq!(vec![ "inactive", "new" ], ids(selected));
+    }
+
+    #[test]
+    fn test_tier_weights() {
+        assert!(TierWeights::parse("").unwrap().is_empty());
+        assert!(TierWeights::parse("MASTER").is_err());
+        assert!(TierWeights::parse("MASTER=x").is_err());
+        assert!(TierWeights::parse("PLASTIC=2").is_err());
+        assert!(TierWeights::parse("GOLD=-1").is_err());
+        assert!(TierWeights::parse("GOLD=NaN").is_err());
+        assert!(TierWeights::parse("GOLD=inf").is_err());
+
+        let weights = TierWeights::parse("UNRANKED=0, master=2, grandmaster=6").unwrap();
+        let quotas = weights.quotas(1000);
+        assert!(quotas.values().sum::<usize>() <= 1000);
+        assert_eq!(0, quotas[&None]);
+        // Proportional, up to rounding down.
+        let gold = quotas[&Some(Tier::GOLD)] as f32;
+        assert!((2.0 * gold - quotas[&Some(Tier::MASTER)] as f32).abs() <= 2.0);
+        assert!((6.0 * gold - quotas[&Some(Tier::GRANDMASTER)] as f32).abs() <= 6.0);
+    }
+}
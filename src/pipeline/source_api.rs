@@ -1,13 +1,17 @@
 use std::collections::HashMap;
 
 use futures::future::join_all;
-use riven::consts::{ Region, Tier, QueueType };
-use riven::RiotApi;
+use riven::consts::{ Region, QueueType };
 
+use crate::api::Api;
+use crate::model::rank::Rank;
+use crate::util::shutdown;
 
+
+// On shutdown, returns the (partial) ranks pulled so far.
 #[allow(dead_code)]
-pub async fn get_ranked_summoners(api: &RiotApi, queue_type: QueueType, region: Region, batch_size: usize)
-    -> HashMap<String, (Tier, String)>
+pub async fn get_ranked_summoners(api: &dyn Api, queue_type: QueueType, region: Region, batch_size: usize)
+    -> HashMap<String, Rank>
 {    
     let mut out = HashMap::with_capacity(65_536);
 
@@ -15,12 +19,15 @@ pub async fn get_ranked_summoners(api: &RiotApi, queue_type: QueueType, region:
         let mut page: usize = 1;
 
         'batchloop: loop {
+            if shutdown::requested() {
+                return out;
+            }
             // Batches of multiple pages.
             let mut league_batch = Vec::with_capacity(batch_size);
 
             for _ in 0..batch_size {
                 league_batch.push(
-                    api.league_exp_v4().get_league_entries(region, queue_type, tier, division, Some(page as i32)));
+                    api.get_league_entries(region, queue_type, tier, division, page as i32));
                 page += 1;
             };
 
@@ -30,8 +37,8 @@ pub async fn get_ranked_summoners(api: &RiotApi, queue_type: QueueType, region:
             for (i, league_entries) in league_batch.into_iter().enumerate() {
                 match league_entries {
                     Err(e) => {
-                        println!("Failed to get league page {}, error: {}, retries: {}, response {:?}.",
-                            page - 10 + i, e.source_reqwest_error(), e.retries(), e.response());
+                        println!("Failed to get league page {}, error: {}.",
+                            page - batch_size + i, e);
                     },
                     Ok(league_entries) => {
                         if 0 == league_entries.len() {
@@ -42,7 +49,12 @@ pub async fn get_ranked_summoners(api: &RiotApi, queue_type: QueueType, region:
                             .into_iter()
                             .map(|league_entry| (
                                 league_entry.summoner_id,
-                                (league_entry.tier, league_entry.league_id),
+                                Rank {
+                                    tier: league_entry.tier,
+                                    division: Some(league_entry.rank),
+                                    league_points: Some(league_entry.league_points),
+                                    league_id: league_entry.league_id,
+                                },
                             ));
                         out.extend(summoners_by_id);
                     },
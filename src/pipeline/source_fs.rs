@@ -1,12 +1,14 @@
 use std::collections::HashMap;
 use std::path::{ Path, PathBuf };
 
-use riven::consts::Tier;
 
+use crate::util::atomic_file;
 use crate::util::csvgz;
 use crate::util::file_find;
 use crate::util::time;
-use crate::model::r#match::Match;
+use crate::model::r#match::{ Match, MATCH_CSV_HEADER };
+use crate::model::rank::Rank;
+use crate::model::stats::ChampStats;
 use crate::model::summoner::{ Summoner, SummonerOldest, SummonerHighestRanked };
 use crate::model::league::League;
 use super::filter;
@@ -43,6 +45,7 @@ pub fn get_all_summoners(path: impl AsRef<Path>)
     }
 }
 
+// Oldest-first selection, see `pipeline::selection` for others.
 #[allow(dead_code)]
 pub fn get_oldest_summoners(path: impl AsRef<Path>, update_size: usize)
     -> std::io::Result<Option<impl Iterator<Item = Summoner>>>
@@ -57,8 +60,9 @@ pub fn get_oldest_summoners(path: impl AsRef<Path>, update_size: usize)
     }))
 }
 
+// Ranks stored in the summoner snapshot (tier, division, LP and league), by encrypted summoner ID.
 pub fn get_ranked_summoners(path: impl AsRef<Path>)
-    -> std::io::Result<HashMap<String, (Tier, String)>>
+    -> std::io::Result<HashMap<String, Rank>>
 {
     let mut out = HashMap::with_capacity(65_536);
 
@@ -66,8 +70,12 @@ pub fn get_ranked_summoners(path: impl AsRef<Path>)
         for summoner in summoners {
             if let Some(tier) = summoner.rank_tier {
                 let league_id = summoner.league_id.expect("Summoner with tier but no league id.");
-                out.insert(summoner.encrypted_summoner_id,
-                    (tier, league_id));
+                out.insert(summoner.encrypted_summoner_id, Rank {
+                    tier: tier,
+                    division: summoner.rank_division,
+                    league_points: summoner.league_points,
+                    league_id: league_id,
+                });
             }
         }
     }
@@ -82,7 +90,7 @@ pub fn write_leagues(path: impl AsRef<Path>, leagues: impl Iterator<Item = Leagu
     for league in leagues {
         writer.serialize(league)?;
     }
-    writer.flush()?;
+    csvgz::finish(writer)?;
 
     Ok(())
 }
@@ -119,22 +127,159 @@ pub fn write_summoners(path: impl AsRef<Path>, summoners: impl Iterator<Item = S
             writer.serialize(summoner.0)?;
         }
     }
-    writer.flush()?;
+    csvgz::finish(writer)?;
 
     Ok(())
 }
 
-pub fn write_matches<'a, I: Iterator<Item = &'a Match>>(
-    dir: &PathBuf, iso_week_str: &str, matches: I) -> std::io::Result<()>
+// Rows that fail to parse are errors, so a corrupt file doesn't abort the caller.
+pub fn get_matches(path: impl AsRef<Path>)
+    -> std::io::Result<impl Iterator<Item = std::io::Result<Match>>>
+{
+    let match_reader = csvgz::reader(path)?
+        .into_deserialize()
+        .map(|match_res| match_res.map_err(std::io::Error::from));
+    Ok(match_reader)
+}
+
+// Checks if a match file's header is the current schema's, regardless of its rows.
+fn is_current_match_schema(path: &PathBuf) -> std::io::Result<bool> {
+    let mut reader = csvgz::reader(path)?;
+    Ok(reader.headers()?.iter().eq(MATCH_CSV_HEADER.iter().cloned()))
+}
+
+// Rewrites an existing match file with an old schema using the current one,
+// since it can't be appended to (header differs). Rows keep their original `schema`, so readers
+// still know which lack draft data. The header alone makes it current, so a file is only rewritten once.
+// Returns if the file was rewritten.
+fn upgrade_match_file(path: &PathBuf) -> std::io::Result<bool> {
+    if !path.exists() || is_current_match_schema(path)? {
+        return Ok(false);
+    }
+    let mut writer = csvgz::writer(path)?;
+    for matche in csvgz::reader(path)?.into_deserialize::<Match>() {
+        writer.serialize(matche?)?;
+    }
+    csvgz::finish(writer)?;
+    Ok(true)
+}
+
+pub fn match_path(dir: &PathBuf, iso_week_str: &str) -> PathBuf {
+    dir.join(format!("matches.{}.csv.gz", iso_week_str))
+}
+
+// Appender to `matches.<iso_week>.csv.gz` in `dir`, creating it if needed.
+// Rows are durable once `csvgz::flush`ed or `csvgz::finish`ed.
+pub fn match_writer(dir: &PathBuf, iso_week_str: &str)
+    -> std::io::Result<csvgz::Writer>
+{
+    let path = match_path(dir, iso_week_str);
+    atomic_file::recover(&path)?;
+    upgrade_match_file(&path)?;
+    csvgz::appender(&path)
+}
+
+// Undoes unfinished appends to match files in the `<major.minor>` dirs of `path_data`,
+// left by a crashed run. Recovered files still holding the run's earlier flushes are recompressed
+// as a single gzip member, so they can be published as is.
+pub fn recover_match_files(path_data: impl AsRef<Path>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(path_data)? {
+        let path_patch = entry?.path();
+        if !path_patch.is_dir() {
+            continue;
+        }
+        let match_paths = file_find::find_all(&path_patch, "matches", "csv.gz")
+            .expect("Failed to find match files.");
+        for match_path in match_paths {
+            if atomic_file::recover(&match_path)? && match_path.exists() && csvgz::is_multi_member(&match_path)? {
+                csvgz::recompress(&match_path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn get_stats(dir: &PathBuf, iso_week_str: &str)
+    -> std::io::Result<Option<impl Iterator<Item = ChampStats>>>
 {
     let mut path = dir.clone();
-    path.push(format!("matches.{}.csv.gz", iso_week_str));
-    let mut writer = csvgz::writer_or_appender(&path)
-        .unwrap_or_else(|e| panic!("Failed to make match writer: {:?}, {}", &path, e));
-    for matche in matches {
-        writer.serialize(matche)?;
+    path.push(format!("stats.{}.csv.gz", iso_week_str));
+    if !path.exists() {
+        return Ok(None);
     }
-    writer.flush()?;
+    let stats_reader = csvgz::reader(path)?
+        .into_deserialize()
+        .map(|stats_res| stats_res.expect("Failed to parse stats."));
+    Ok(Some(stats_reader))
+}
+
+pub fn write_stats(dir: &PathBuf, iso_week_str: &str, stats: impl Iterator<Item = ChampStats>)
+    -> std::io::Result<()>
+{
+    let mut path = dir.clone();
+    path.push(format!("stats.{}.csv.gz", iso_week_str));
+    let mut writer = csvgz::writer(&path)
+        .unwrap_or_else(|e| panic!("Failed to make stats writer: {:?}, {}", &path, e));
+    for champ_stats in stats {
+        writer.serialize(champ_stats)?;
+    }
+    csvgz::finish(writer)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_upgrade_match_file() {
+        let dir = std::env::temp_dir().join("pbw_source_fs_upgrade");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("matches.2020-W09.csv.gz");
+
+        // Version 1 file.
+        let mut writer = csvgz::writer(&path).unwrap();
+        writer.write_record(&[ "match_id", "rank_tier", "ts" ]).unwrap();
+        writer.write_record(&[ "3300000001", "GOLD", "1582700000000" ]).unwrap();
+        csvgz::finish(writer).unwrap();
+
+        assert!(upgrade_match_file(&path).unwrap());
+        // Second open doesn't rewrite.
+        assert!(!upgrade_match_file(&path).unwrap());
+        let matches = get_matches(&path).unwrap().collect::<std::io::Result<Vec<_>>>().unwrap();
+        assert_eq!(1, matches.len());
+        assert_eq!(1, matches[0].schema);
+
+        // Header only, already current.
+        let mut writer = csvgz::writer(&path).unwrap();
+        writer.write_record(&MATCH_CSV_HEADER).unwrap();
+        csvgz::finish(writer).unwrap();
+        assert!(!upgrade_match_file(&path).unwrap());
+    }
+
+    #[test]
+    fn test_recover_match_files() {
+        let path_data = std::env::temp_dir().join("pbw_source_fs_recover");
+        let _ = std::fs::remove_dir_all(&path_data);
+        let path_patch = path_data.join("10.4");
+        std::fs::create_dir_all(&path_patch).unwrap();
+        let path = match_path(&path_patch, "2020-W09");
+
+        // Crash after two flushes, with a third unflushed.
+        let mut writer = csvgz::appender(&path).unwrap();
+        writer.write_record(&[ "match_id" ]).unwrap();
+        writer.write_record(&[ "1" ]).unwrap();
+        let mut writer = csvgz::flush(writer).unwrap();
+        writer.write_record(&[ "2" ]).unwrap();
+        let mut writer = csvgz::flush(writer).unwrap();
+        writer.write_record(&[ "3" ]).unwrap();
+        std::mem::forget(writer);
+        assert!(csvgz::is_multi_member(&path).unwrap());
+
+        recover_match_files(&path_data).unwrap();
+        assert!(!atomic_file::journal_path(&path).exists());
+        assert!(!csvgz::is_multi_member(&path).unwrap());
+        assert_eq!(2, csvgz::reader(&path).unwrap().records().count());
+    }
+}
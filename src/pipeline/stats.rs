@@ -0,0 +1,390 @@
+use std::collections::{ BTreeMap, HashMap, HashSet };
+use std::path::{ Path, PathBuf };
+
+use riven::consts::Tier;
+
+use crate::model::r#match::{ Match, MatchFileKey };
+use crate::model::stats::ChampStats;
+use crate::pipeline::source_fs;
+use crate::util::file_find;
+use crate::util::lol;
+
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChampCounts {
+    pub picks: u32,
+    pub bans: u32,
+    pub wins: u32,
+}
+
+impl ChampCounts {
+    pub fn merge(&mut self, other: &ChampCounts) {
+        self.picks += other.picks;
+        self.bans  += other.bans;
+        self.wins  += other.wins;
+    }
+}
+
+// Counts for all matches in a single average tier bucket.
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct TierCounts {
+    pub games: u32,
+    pub champs: BTreeMap<i16, ChampCounts>,
+}
+
+impl TierCounts {
+    pub fn merge(&mut self, other: TierCounts) {
+        self.games += other.games;
+        for (champion_id, counts) in other.champs {
+            self.champs.entry(champion_id)
+                .or_insert_with(ChampCounts::default)
+                .merge(&counts);
+        }
+    }
+}
+
+// Counts for a single stats file (patch + ISO week).
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct StatsTable {
+    pub tiers: BTreeMap<Option<Tier>, TierCounts>,
+}
+
+impl StatsTable {
+    // Rebuild a table from the rows of a stats file.
+    pub fn from_rows(rows: impl Iterator<Item = ChampStats>) -> Self {
+        let mut out = Self::default();
+        for row in rows {
+            let tier_counts = out.tiers.entry(row.rank_tier).or_insert_with(TierCounts::default);
+            // Games is repeated in every row of the tier.
+            tier_counts.games = row.games;
+            tier_counts.champs.insert(row.champion_id, ChampCounts {
+                picks: row.picks,
+                bans: row.bans,
+                wins: row.wins,
+            });
+        }
+        out
+    }
+
+    // Adds all counts from `other`. Commutative, so order of runs doesn't matter.
+    pub fn merge(&mut self, other: StatsTable) {
+        for (rank_tier, tier_counts) in other.tiers {
+            self.tiers.entry(rank_tier)
+                .or_insert_with(TierCounts::default)
+                .merge(tier_counts);
+        }
+    }
+
+    // Adds a match with draft data. Matches stored before schema version 2 have no picks and are skipped.
+    pub fn add_match(&mut self, matche: &Match) -> bool {
+        if matche.picks.is_empty() {
+            return false;
+        }
+
+        let tier_counts = self.tiers.entry(matche.rank_tier).or_insert_with(TierCounts::default);
+        tier_counts.games += 1;
+
+        for pick in matche.picks.iter() {
+            let counts = tier_counts.champs.entry(pick.champion_id)
+                .or_insert_with(ChampCounts::default);
+            counts.picks += 1;
+            if Some(pick.team) == matche.winner {
+                counts.wins += 1;
+            }
+        }
+        for ban in matche.bans.iter() {
+            // Negative ID means no ban.
+            if ban.champion_id < 0 {
+                continue;
+            }
+            tier_counts.champs.entry(ban.champion_id)
+                .or_insert_with(ChampCounts::default)
+                .bans += 1;
+        }
+        true
+    }
+
+    pub fn rows<'a>(&'a self) -> impl Iterator<Item = ChampStats> + 'a {
+        self.tiers.iter().flat_map(|(rank_tier, tier_counts)| {
+            tier_counts.champs.iter().map(move |(champion_id, counts)| ChampStats {
+                rank_tier: *rank_tier,
+                champion_id: *champion_id,
+                games: tier_counts.games,
+                picks: counts.picks,
+                bans: counts.bans,
+                wins: counts.wins,
+            })
+        })
+    }
+}
+
+// Streaming stats accumulator, one table per match file key.
+#[derive(Default)]
+pub struct Stats {
+    pub tables: HashMap<MatchFileKey, StatsTable>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_match(&mut self, match_key: MatchFileKey, matche: &Match) {
+        self.tables.entry(match_key)
+            .or_insert_with(StatsTable::default)
+            .add_match(matche);
+    }
+
+    #[allow(dead_code)]
+    pub fn merge(&mut self, other: Stats) {
+        for (match_key, table) in other.tables {
+            self.tables.entry(match_key)
+                .or_insert_with(StatsTable::default)
+                .merge(table);
+        }
+    }
+}
+
+// Merges `table` into the existing stats file (if any) and atomically rewrites it.
+pub fn update_stats_file(dir: &PathBuf, iso_week_str: &str, mut table: StatsTable)
+    -> std::io::Result<()>
+{
+    if let Some(rows) = source_fs::get_stats(dir, iso_week_str)? {
+        table.merge(StatsTable::from_rows(rows));
+    }
+    source_fs::write_stats(dir, iso_week_str, table.rows())
+}
+
+// Rebuilds every stats file under `path_data` (`<major.minor>/stats.*.csv.gz`) from scratch,
+// using only the stored `matches.*.csv.gz` files. Stats files without a match file are removed.
+// Returns the number of stats files written and the number of matches counted.
+pub fn recompute_stats(path_data: impl AsRef<Path>) -> std::io::Result<(usize, usize)> {
+    let mut files = 0;
+    let mut count = 0;
+    for entry in std::fs::read_dir(path_data)? {
+        let path_patch = entry?.path();
+        // Only `<major.minor>` dirs (skips `local`).
+        let is_patch = path_patch.is_dir() && path_patch.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(lol::parse_version)
+            .is_some();
+        if !is_patch {
+            continue;
+        }
+
+        let match_paths = file_find::find_all(&path_patch, "matches", "csv.gz")
+            .expect("Failed to find match files.");
+        let mut iso_week_strs = HashSet::new();
+        for match_path in match_paths {
+            let iso_week_str = file_find::get_infix(&match_path, "matches", "csv.gz")
+                .expect("Bad match file name.")
+                .to_owned();
+            iso_week_strs.insert(iso_week_str.clone());
+
+            let (match_ids, counted) = rebuild_stats_file(&path_patch, &iso_week_str)?;
+            let skipped = match_ids.len() - counted;
+            if 0 < skipped {
+                println!("  {:?}: skipped {} matches without draft data.", match_path, skipped);
+            }
+            count += counted;
+            files += 1;
+        }
+
+        let stats_paths = file_find::find_all(&path_patch, "stats", "csv.gz")
+            .expect("Failed to find stats files.");
+        for stats_path in stats_paths {
+            let is_orphan = file_find::get_infix(&stats_path, "stats", "csv.gz")
+                .map(|iso_week_str| !iso_week_strs.contains(iso_week_str))
+                .unwrap_or(false);
+            if is_orphan {
+                println!("  {:?}: removed, no match file.", stats_path);
+                std::fs::remove_file(&stats_path)?;
+            }
+        }
+    }
+    Ok((files, count))
+}
+
+// Rewrites the stats file of the `matches.<iso_week>.csv.gz` file in `path_patch` from its rows.
+// Returns the IDs of all matches in it and the number counted (matches without draft data are skipped).
+fn rebuild_stats_file(path_patch: &PathBuf, iso_week_str: &str) -> std::io::Result<(Vec<u64>, usize)> {
+    let mut table = StatsTable::default();
+    let mut match_ids = vec![];
+    let mut count = 0;
+    for matche in source_fs::get_matches(source_fs::match_path(path_patch, iso_week_str))? {
+        let matche = matche?;
+        match_ids.push(matche.match_id);
+        if table.add_match(&matche) {
+            count += 1;
+        }
+    }
+    source_fs::write_stats(path_patch, iso_week_str, table.rows())?;
+    Ok((match_ids, count))
+}
+
+// Repairs files a crashed run was writing, `stale_stats` from its checkpoint (patch dir and ISO week).
+// Their stats are rebuilt from their rows, and matches already in them are removed from `pending_match_ids`,
+// so they are neither missing from stats nor fetched and written twice.
+// Match files must be recovered first, see `source_fs::recover_match_files`. Returns the number of pending matches removed.
+pub fn repair_stale_stats(path_data: impl AsRef<Path>, stale_stats: &[(String, String)],
+    pending_match_ids: &mut Vec<i64>)
+    -> std::io::Result<usize>
+{
+    let mut saved = HashSet::new();
+    for (patch, iso_week_str) in stale_stats {
+        let path_patch = path_data.as_ref().join(patch);
+        // Never synced any rows.
+        if !source_fs::match_path(&path_patch, iso_week_str).exists() {
+            continue;
+        }
+        let (match_ids, _count) = rebuild_stats_file(&path_patch, iso_week_str)?;
+        saved.extend(match_ids);
+    }
+    let len = pending_match_ids.len();
+    pending_match_ids.retain(|match_id| !saved.contains(&(*match_id as u64)));
+    Ok(len - pending_match_ids.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn table(rank_tier: Option<Tier>, games: u32, champs: &[(i16, u32, u32, u32)]) -> StatsTable {
+        StatsTable::from_rows(champs.iter().map(|&(champion_id, picks, bans, wins)| ChampStats {
+            rank_tier, champion_id, games, picks, bans, wins,
+        }))
+    }
+
+    #[test]
+    fn test_merge_commutative() {
+        let a = || table(Some(Tier::GOLD), 10, &[ (1, 3, 2, 1), (266, 5, 0, 4) ]);
+        let b = || table(Some(Tier::GOLD), 4,  &[ (1, 1, 1, 0), (103, 2, 3, 2) ]);
+        let c = || table(None,             2,  &[ (103, 1, 0, 1) ]);
+
+        let mut abc = a();
+        abc.merge(b());
+        abc.merge(c());
+
+        let mut cba = c();
+        cba.merge(b());
+        cba.merge(a());
+
+        assert_eq!(abc, cba);
+
+        let gold = &abc.tiers[&Some(Tier::GOLD)];
+        assert_eq!(14, gold.games);
+        assert_eq!(ChampCounts { picks: 4, bans: 3, wins: 1 }, gold.champs[&1]);
+        assert_eq!(2, abc.tiers[&None].games);
+    }
+
+    fn draft_match(match_id: u64) -> Match {
+        use crate::model::r#match::{ Ban, Pick, MATCH_SCHEMA_VERSION };
+
+        let pick = |champion_id, team| Pick {
+            champion_id: champion_id,
+            team: team,
+            role: "NONE".to_owned(),
+            lane: "NONE".to_owned(),
+        };
+        Match {
+            match_id: match_id,
+            rank_tier: Some(Tier::GOLD),
+            ts: 0,
+            schema: MATCH_SCHEMA_VERSION,
+            game_version: None,
+            game_duration: None,
+            winner: Some(100),
+            picks: vec![ pick(1, 100), pick(2, 200) ],
+            bans: vec![
+                Ban { champion_id: 2, team: 100, pick_turn: 1 },
+                Ban { champion_id: -1, team: 200, pick_turn: 2 },
+            ],
+            rank_division: None,
+            rank_score: None,
+        }
+    }
+
+    #[test]
+    fn test_add_match() {
+        let matche = draft_match(1);
+
+        let mut table = StatsTable::default();
+        assert!(table.add_match(&matche));
+        let gold = &table.tiers[&Some(Tier::GOLD)];
+        assert_eq!(1, gold.games);
+        assert_eq!(ChampCounts { picks: 1, bans: 0, wins: 1 }, gold.champs[&1]);
+        assert_eq!(ChampCounts { picks: 1, bans: 1, wins: 0 }, gold.champs[&2]);
+        assert_eq!(2, gold.champs.len());
+
+        let legacy = Match {
+            picks: vec![],
+            bans: vec![],
+            ..matche
+        };
+        assert!(!table.add_match(&legacy));
+        assert_eq!(1, table.tiers[&Some(Tier::GOLD)].games);
+    }
+
+    #[test]
+    fn test_rows_roundtrip() {
+        let a = table(Some(Tier::MASTER), 7, &[ (1, 3, 2, 1), (266, 5, 0, 4) ]);
+        let b = StatsTable::from_rows(a.rows());
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn test_repair_stale_stats() {
+        let path_data = std::env::temp_dir().join("pbw_stats_repair");
+        let _ = std::fs::remove_dir_all(&path_data);
+        let path_patch = path_data.join("10.4");
+        std::fs::create_dir_all(&path_patch).unwrap();
+
+        // Rows of matches 1 and 2 were synced, but only match 1 made it into stats.
+        let mut writer = crate::util::csvgz::writer(source_fs::match_path(&path_patch, "2020-W09")).unwrap();
+        writer.serialize(draft_match(1)).unwrap();
+        writer.serialize(draft_match(2)).unwrap();
+        crate::util::csvgz::finish(writer).unwrap();
+        let mut stale = StatsTable::default();
+        stale.add_match(&draft_match(1));
+        source_fs::write_stats(&path_patch, "2020-W09", stale.rows()).unwrap();
+
+        let stale_stats = vec![
+            ("10.4".to_owned(), "2020-W09".to_owned()),
+            ("10.4".to_owned(), "2020-W10".to_owned()),
+        ];
+        let mut pending_match_ids = vec![ 2, 3 ];
+        assert_eq!(1, repair_stale_stats(&path_data, &stale_stats, &mut pending_match_ids).unwrap());
+        assert_eq!(vec![ 3 ], pending_match_ids);
+
+        let table = StatsTable::from_rows(source_fs::get_stats(&path_patch, "2020-W09").unwrap().unwrap());
+        assert_eq!(2, table.tiers[&Some(Tier::GOLD)].games);
+        assert!(source_fs::get_stats(&path_patch, "2020-W10").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_recompute_stats() {
+        use crate::model::r#match::MATCH_CSV_HEADER;
+
+        let path_data = std::env::temp_dir().join("pbw_stats_recompute");
+        let _ = std::fs::remove_dir_all(&path_data);
+        let path_patch = path_data.join("10.4");
+        std::fs::create_dir_all(&path_patch).unwrap();
+
+        let mut writer = crate::util::csvgz::writer(source_fs::match_path(&path_patch, "2020-W09")).unwrap();
+        writer.serialize(draft_match(1)).unwrap();
+        crate::util::csvgz::finish(writer).unwrap();
+        // Orphan, its match file is gone.
+        source_fs::write_stats(&path_patch, "2020-W08", StatsTable::default().rows()).unwrap();
+
+        assert_eq!((1, 1), recompute_stats(&path_data).unwrap());
+        assert!(source_fs::get_stats(&path_patch, "2020-W09").unwrap().is_some());
+        assert!(source_fs::get_stats(&path_patch, "2020-W08").unwrap().is_none());
+
+        // Corrupt row is an error, not a panic.
+        let mut writer = crate::util::csvgz::writer(source_fs::match_path(&path_patch, "2020-W09")).unwrap();
+        writer.serialize(draft_match(1)).unwrap();
+        writer.write_record(&[ "x"; MATCH_CSV_HEADER.len() ]).unwrap();
+        crate::util::csvgz::finish(writer).unwrap();
+        assert!(recompute_stats(&path_data).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+use std::path::{ Path, PathBuf };
+
+use chrono::{ DateTime, Duration };
+use chrono::offset::Utc;
+use serde::{ Serialize, Deserialize };
+
+use crate::util::atomic_file::AtomicFile;
+use crate::util::hybitset::{ HyBitSet, SEGMENT_LEN };
+
+const FILE_NAME: &'static str = "match_watermark.json";
+
+// Extra time kept past the lookbehind before pruning, since matchlist timestamps are game starts.
+pub const PRUNE_SLACK_DAYS: i64 = 2;
+
+// Approximate match ID -> time mapping: the newest timestamp (epoch millis) seen per hybitset segment.
+// Stored next to the hybitset snapshots in `local`.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct MatchWatermark {
+    segments: BTreeMap<usize, i64>,
+}
+
+impl MatchWatermark {
+    pub fn observe(&mut self, match_id: usize, timestamp_millis: i64) {
+        let ts = self.segments.entry(HyBitSet::get_seg_id(match_id)).or_insert(timestamp_millis);
+        if *ts < timestamp_millis {
+            *ts = timestamp_millis;
+        }
+    }
+
+    // Removes match IDs from `match_hbs` in the prefix of segments whose newest match is before `cutoff`.
+    // Match IDs increase over time, so unwatermarked segments below that prefix (from before watermarks) go too.
+    // Returns the number of IDs removed.
+    pub fn prune(&mut self, match_hbs: &mut HyBitSet, cutoff: DateTime<Utc>) -> usize {
+        let cutoff_millis = cutoff.timestamp_millis();
+        let end = self.segments.iter()
+            .take_while(|(_seg_id, ts)| **ts < cutoff_millis)
+            .last()
+            .map(|(seg_id, _ts)| seg_id + SEGMENT_LEN);
+        match end {
+            Some(end) => {
+                self.segments = self.segments.split_off(&end);
+                match_hbs.remove_range(..end)
+            },
+            None => 0,
+        }
+    }
+}
+
+// Cutoff for pruning, given the crawl's start time (`now - lookbehind`).
+pub fn prune_cutoff(starttime: DateTime<Utc>) -> DateTime<Utc> {
+    starttime - Duration::days(PRUNE_SLACK_DAYS)
+}
+
+fn path(path_data_local: &Path) -> PathBuf {
+    path_data_local.join(FILE_NAME)
+}
+
+pub fn read_watermark(path_data_local: impl AsRef<Path>) -> std::io::Result<MatchWatermark> {
+    let path = path(path_data_local.as_ref());
+    if !path.exists() {
+        return Ok(MatchWatermark::default());
+    }
+    let bytes = std::fs::read(path)?;
+    let watermark = serde_json::from_slice(&bytes)?;
+    Ok(watermark)
+}
+
+pub fn write_watermark(path_data_local: impl AsRef<Path>, watermark: &MatchWatermark) -> std::io::Result<()> {
+    let bytes = serde_json::to_vec(watermark)?;
+    let mut file = AtomicFile::create(path(path_data_local.as_ref()))?;
+    std::io::Write::write_all(&mut file, &bytes)?;
+    file.commit()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_prune() {
+        let day = 24 * 3600 * 1000;
+        let now = Utc.ymd(2020, 3, 31).and_hms(0, 0, 0);
+        let now_millis = now.timestamp_millis();
+
+        let mut match_hbs = HyBitSet::new();
+        let mut watermark = MatchWatermark::default();
+        // Legacy ID with no watermark, then old, then a segment with both old and recent, then recent.
+        match_hbs.insert(10);
+        for (match_id, ts) in &[
+            (SEGMENT_LEN + 1, now_millis - 30 * day),
+            (2 * SEGMENT_LEN + 1, now_millis - 30 * day),
+            (2 * SEGMENT_LEN + 2, now_millis - 1 * day),
+            (5 * SEGMENT_LEN, now_millis - 1 * day),
+            (9 * SEGMENT_LEN, now_millis - 30 * day),
+        ] {
+            match_hbs.insert(*match_id);
+            watermark.observe(*match_id, *ts);
+        }
+
+        let removed = watermark.prune(&mut match_hbs, now - Duration::days(10));
+        // Stops at the first recent segment, the out-of-order old one later is kept.
+        assert_eq!(2, removed);
+        assert!(!match_hbs.contains(10));
+        assert!(!match_hbs.contains(SEGMENT_LEN + 1));
+        assert!(match_hbs.contains(2 * SEGMENT_LEN + 1));
+        assert!(match_hbs.contains(9 * SEGMENT_LEN));
+        assert_eq!(3, watermark.segments.len());
+
+        assert_eq!(0, watermark.prune(&mut match_hbs, now - Duration::days(10)));
+    }
+}
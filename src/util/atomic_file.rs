@@ -0,0 +1,211 @@
+use std::ffi::OsString;
+use std::fs::{ File, OpenOptions };
+use std::io::Write;
+use std::path::{ Path, PathBuf };
+
+// Suffix of in-progress files. Readers' `name.*.ext` globs never match these.
+pub const TEMP_SUFFIX: &'static str = ".tmp";
+// Suffix of the journal of an in-progress append, holding the file's last committed length.
+pub const JOURNAL_SUFFIX: &'static str = ".append";
+
+fn with_suffix<P: AsRef<Path>>(path: P, suffix: &str) -> PathBuf {
+    let mut name: OsString = path.as_ref().as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+pub fn temp_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    with_suffix(path, TEMP_SUFFIX)
+}
+
+pub fn journal_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    with_suffix(path, JOURNAL_SUFFIX)
+}
+
+fn write_journal(path: &Path, len: u64) -> std::io::Result<()> {
+    let mut file = AtomicFile::create(journal_path(path))?;
+    file.write_all(len.to_string().as_bytes())?;
+    file.commit()
+}
+
+// Truncates `path` to `len`, removing it if empty, then removes the journal.
+fn truncate(path: &Path, len: u64) -> std::io::Result<()> {
+    if 0 == len {
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    else {
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(len)?;
+        file.sync_all()?;
+    }
+    std::fs::remove_file(journal_path(path))
+}
+
+// Undoes an append to `path` that was never committed (from a crash), if any.
+// Returns if there was one.
+pub fn recover<P: AsRef<Path>>(path: P) -> std::io::Result<bool> {
+    let path = path.as_ref();
+    let journal = journal_path(path);
+    if !journal.exists() {
+        return Ok(false);
+    }
+    let len = std::fs::read_to_string(&journal)?.trim().parse::<u64>()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData,
+            format!("Bad append journal {:?}: {}", journal, e)))?;
+    truncate(path, len)?;
+    Ok(true)
+}
+
+// File written at a temp path next to `path`, then fsynced and renamed into place by `commit`.
+// If dropped without committing the temp file is removed, so `path` is never left partial.
+// Appends instead go to `path` itself, see `append`.
+pub struct AtomicFile {
+    file: File,
+    path: PathBuf,
+    // Last synced length when appending.
+    appending: Option<u64>,
+    committed: bool,
+}
+
+impl AtomicFile {
+    // Starts a new, empty file.
+    pub fn create<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let file = File::create(temp_path(&path))?;
+        Ok(Self { file: file, path: path, appending: None, committed: false })
+    }
+
+    // Appends to `path` in place, creating it if missing.
+    // The length before the append is journaled, so anything not synced is truncated off
+    // when dropped without committing, or by `recover` after a crash.
+    pub fn append<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        recover(&path)?;
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+        write_journal(&path, len)?;
+        Ok(Self { file: file, path: path, appending: Some(len), committed: false })
+    }
+
+    // Current length, including unsynced writes.
+    fn len(&self) -> std::io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    pub fn is_empty(&self) -> std::io::Result<bool> {
+        Ok(0 == self.len()?)
+    }
+
+    // Fsyncs everything written so far. When appending, it is also kept if later writes are undone.
+    pub fn sync(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        self.file.sync_all()?;
+        if self.appending.is_some() {
+            let len = self.len()?;
+            write_journal(&self.path, len)?;
+            self.appending = Some(len);
+        }
+        Ok(())
+    }
+
+    pub fn commit(mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        self.file.sync_all()?;
+        if self.appending.is_some() {
+            std::fs::remove_file(journal_path(&self.path))?;
+        }
+        else {
+            std::fs::rename(temp_path(&self.path), &self.path)?;
+        }
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = match self.appending {
+                Some(len) => truncate(&self.path, len),
+                None => std::fs::remove_file(temp_path(&self.path)),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_commit_and_drop() {
+        let dir = std::env::temp_dir().join("pbw_atomic_file");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("x.txt");
+        let _ = std::fs::remove_file(&path);
+
+        let mut file = AtomicFile::create(&path).unwrap();
+        file.write_all(b"abc").unwrap();
+        assert!(!path.exists());
+        file.commit().unwrap();
+        assert_eq!(b"abc".to_vec(), std::fs::read(&path).unwrap());
+
+        let mut file = AtomicFile::append(&path).unwrap();
+        file.write_all(b"def").unwrap();
+        drop(file);
+        assert!(!journal_path(&path).exists());
+        assert_eq!(b"abc".to_vec(), std::fs::read(&path).unwrap());
+
+        let mut file = AtomicFile::append(&path).unwrap();
+        file.write_all(b"def").unwrap();
+        file.sync().unwrap();
+        file.write_all(b"ghi").unwrap();
+        drop(file);
+        assert_eq!(b"abcdef".to_vec(), std::fs::read(&path).unwrap());
+
+        let mut file = AtomicFile::append(&path).unwrap();
+        file.write_all(b"ghi").unwrap();
+        file.commit().unwrap();
+        assert!(!journal_path(&path).exists());
+        assert_eq!(b"abcdefghi".to_vec(), std::fs::read(&path).unwrap());
+    }
+
+    #[test]
+    fn test_recover() {
+        let dir = std::env::temp_dir().join("pbw_atomic_file_recover");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("x.txt");
+        let _ = std::fs::remove_file(&path);
+
+        // Crash during the first append, nothing was there before.
+        let mut file = AtomicFile::append(&path).unwrap();
+        file.write_all(b"abc").unwrap();
+        std::mem::forget(file);
+        assert!(recover(&path).unwrap());
+        assert!(!path.exists());
+        assert!(!recover(&path).unwrap());
+
+        // Crash after a sync.
+        let mut file = AtomicFile::append(&path).unwrap();
+        file.write_all(b"abc").unwrap();
+        file.sync().unwrap();
+        file.write_all(b"def").unwrap();
+        std::mem::forget(file);
+        // Recovered by the next append.
+        AtomicFile::append(&path).unwrap().commit().unwrap();
+        assert!(!journal_path(&path).exists());
+        assert_eq!(b"abc".to_vec(), std::fs::read(&path).unwrap());
+    }
+}
@@ -1,41 +1,146 @@
-use std::fs::{ File, OpenOptions };
+use std::fs::File;
 use std::path::Path;
 
 use flate2::Compression;
 use flate2::write::GzEncoder;
-use flate2::read::GzDecoder;
+use flate2::read::{ GzDecoder, MultiGzDecoder };
 
+use super::atomic_file::{ self, AtomicFile };
 
+// Writers go to a temp file, call `finish` to move it into place.
+// Appenders go to the file itself, `flush` and `finish` make their rows durable.
+pub type Writer = csv::Writer<GzEncoder<AtomicFile>>;
+
+// Reads all gzip members, since `appender` adds a new member on each flush.
 #[allow(dead_code)]
-pub fn reader<P: AsRef<Path>>(path: P) -> std::io::Result<csv::Reader<GzDecoder<File>>> {
+pub fn reader<P: AsRef<Path>>(path: P) -> std::io::Result<csv::Reader<MultiGzDecoder<File>>> {
     let file    = File::open(path)?;
-    let decoder = GzDecoder::new(file);
+    let decoder = MultiGzDecoder::new(file);
     let reader  = csv::Reader::from_reader(decoder);
     Ok(reader)
 }
 
-#[allow(dead_code)]
-pub fn writer_or_appender<P: AsRef<Path>>(path: P) -> std::io::Result<csv::Writer<GzEncoder<File>>> {
-    if path.as_ref().exists() {
-        appender(path)
-    }
-    else {
-        writer(path)
-    }
-}
-
-pub fn writer<P: AsRef<Path>>(path: P) -> std::io::Result<csv::Writer<GzEncoder<File>>> {
-    let file    = File::create(path)?;
+pub fn writer<P: AsRef<Path>>(path: P) -> std::io::Result<Writer> {
+    let file    = AtomicFile::create(path)?;
     let encoder = GzEncoder::new(file, Compression::default());
     let writer  = csv::Writer::from_writer(encoder);
     Ok(writer)
 }
 
-pub fn appender<P: AsRef<Path>>(path: P) -> std::io::Result<csv::Writer<GzEncoder<File>>> {
-    let file    = OpenOptions::new().write(true).append(true).open(path)?;
-    let encoder = GzEncoder::new(file, Compression::default());
-    let writer  = csv::WriterBuilder::new()
+// Appends rows to `path` in a new gzip member, creating it (with headers) if missing.
+// Rows not yet flushed or finished are undone on drop or after a crash, see `AtomicFile::append`.
+// The file is left with a member per flush, `recompress` makes it a plain gzip stream (which browsers handle).
+pub fn appender<P: AsRef<Path>>(path: P) -> std::io::Result<Writer> {
+    let file = AtomicFile::append(path)?;
+    let is_new = file.is_empty()?;
+    let writer = csv::WriterBuilder::new()
+        .has_headers(is_new)
+        .from_writer(GzEncoder::new(file, Compression::default()));
+    Ok(writer)
+}
+
+fn into_file(writer: Writer) -> std::io::Result<AtomicFile> {
+    let encoder = writer.into_inner()
+        .map_err(|e| std::io::Error::new(e.error().kind(), e.to_string()))?;
+    encoder.finish()
+}
+
+// Ends the appender's current gzip member and syncs it, so all rows so far are durable,
+// then continues in a new member.
+pub fn flush(writer: Writer) -> std::io::Result<Writer> {
+    let mut file = into_file(writer)?;
+    file.sync()?;
+    let writer = csv::WriterBuilder::new()
         .has_headers(false)
-        .from_writer(encoder);
+        .from_writer(GzEncoder::new(file, Compression::default()));
     Ok(writer)
 }
+
+// Checks if the file has more than one gzip member, by comparing what a single-member decoder reads.
+pub fn is_multi_member<P: AsRef<Path>>(path: P) -> std::io::Result<bool> {
+    let single = std::io::copy(&mut GzDecoder::new(File::open(&path)?), &mut std::io::sink())?;
+    let multi  = std::io::copy(&mut MultiGzDecoder::new(File::open(&path)?), &mut std::io::sink())?;
+    Ok(single != multi)
+}
+
+// Rewrites a file as a single gzip member, via a temp file.
+pub fn recompress<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
+    atomic_file::recover(&path)?;
+    let mut decoder = MultiGzDecoder::new(File::open(&path)?);
+    let mut encoder = GzEncoder::new(AtomicFile::create(&path)?, Compression::default());
+    std::io::copy(&mut decoder, &mut encoder)?;
+    encoder.finish()?.commit()
+}
+
+// Finishes the gzip stream and fsyncs, then renames the file into place (for writers).
+// A writer dropped without this leaves the original file untouched.
+pub fn finish(writer: Writer) -> std::io::Result<()> {
+    into_file(writer)?.commit()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{ Read, Write };
+
+    #[derive(serde::Serialize)]
+    struct Row {
+        a: u32,
+        b: u32,
+    }
+
+    #[test]
+    fn test_append() {
+        let dir = std::env::temp_dir().join("pbw_csvgz_append");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("x.csv.gz");
+        let _ = std::fs::remove_file(&path);
+
+        // Headers only for a new file.
+        let mut w = appender(&path).unwrap();
+        w.serialize(Row { a: 1, b: 2 }).unwrap();
+        let mut w = flush(w).unwrap();
+        w.serialize(Row { a: 3, b: 4 }).unwrap();
+        finish(w).unwrap();
+
+        let mut w = appender(&path).unwrap();
+        w.serialize(Row { a: 5, b: 6 }).unwrap();
+        let mut w = flush(w).unwrap();
+        // Dropped, not flushed.
+        w.serialize(Row { a: 7, b: 8 }).unwrap();
+        drop(w);
+
+        let mut text = String::new();
+        MultiGzDecoder::new(File::open(&path).unwrap()).read_to_string(&mut text).unwrap();
+        assert_eq!("a,b\n1,2\n3,4\n5,6\n", text);
+        assert!(is_multi_member(&path).unwrap());
+
+        // A single-member decoder sees everything after recompressing.
+        recompress(&path).unwrap();
+        let mut text = String::new();
+        GzDecoder::new(File::open(&path).unwrap()).read_to_string(&mut text).unwrap();
+        assert_eq!("a,b\n1,2\n3,4\n5,6\n", text);
+    }
+
+    #[test]
+    fn test_recompress() {
+        let dir = std::env::temp_dir().join("pbw_csvgz_recompress");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("x.csv.gz");
+
+        // Two members, like old appends made.
+        let mut bytes = vec![];
+        for text in &[ "a,b\n1,2\n", "3,4\n" ] {
+            let mut encoder = GzEncoder::new(vec![], Compression::default());
+            encoder.write_all(text.as_bytes()).unwrap();
+            bytes.extend(encoder.finish().unwrap());
+        }
+        std::fs::write(&path, bytes).unwrap();
+        assert!(is_multi_member(&path).unwrap());
+
+        recompress(&path).unwrap();
+        assert!(!is_multi_member(&path).unwrap());
+        let rows = reader(&path).unwrap().into_records().count();
+        assert_eq!(2, rows);
+    }
+}
@@ -12,6 +12,7 @@ lazy_static! {
 }
 
 // TODO: really need to distinguish between "no files found" and "it fucked up".
+// Leftover `*.tmp` files from interrupted writes don't end with `ext`, so are never matched.
 pub fn find_latest(path: impl AsRef<Path>, name: &str, ext: &str) -> Result<Option<PathBuf>, glob::GlobError> {
 
     let mut latest: Option<PathBuf> = None;
@@ -28,6 +29,29 @@ pub fn find_latest(path: impl AsRef<Path>, name: &str, ext: &str) -> Result<Opti
     Ok(latest)
 }
 
+// All matching files, sorted by name.
+pub fn find_all(path: impl AsRef<Path>, name: &str, ext: &str) -> Result<Vec<PathBuf>, glob::GlobError> {
+    let pattern = format!("{}/{}.*.{}",
+        path.as_ref().to_str().expect("path has unicode"),
+        name, ext);
+
+    let mut out = glob_with(&pattern, *MATCH_OPTIONS).expect("bad glob")
+        .collect::<Result<Vec<_>, _>>()?;
+    out.sort();
+    Ok(out)
+}
+
+// Gets the `*` part of a `name.*.ext` file name.
+pub fn get_infix<'a>(path: &'a Path, name: &str, ext: &str) -> Option<&'a str> {
+    let file_name = path.file_name()?.to_str()?;
+    if file_name.len() < name.len() + ext.len() + 2 {
+        return None;
+    }
+    let prefix_len = name.len() + 1;
+    let suffix_len = ext.len() + 1;
+    Some(&file_name[prefix_len..(file_name.len() - suffix_len)])
+}
+
 // pub fn find_after_datetime(path: impl AsRef<Path>, name: &str, ext: &str, starttime: DateTime<Utc>) -> Vec<PathBuf> {
     
 //     let mut results: Vec<PathBuf> = vec![];
@@ -1,55 +1,375 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::io::{ Read, Write };
+use std::ops::{ Bound, RangeBounds };
 
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
 use serde::{ Deserialize, Serialize, Deserializer, Serializer };
 
+use super::error::PbwError;
+
 const SEGMENT_BYTE_LEN: usize = 1024;
 const BITS_PER_BYTE:    usize = 8;
-const SEGMENT_LEN:      usize = SEGMENT_BYTE_LEN * BITS_PER_BYTE;
+pub const SEGMENT_LEN:  usize = SEGMENT_BYTE_LEN * BITS_PER_BYTE;
+
+// Offsets within a segment fit in a u16.
+type Offset = u16;
+
+// Largest array segment, at which point it is as large as a bitmap.
+const ARRAY_MAX_LEN: usize = SEGMENT_BYTE_LEN / 2;
+
+// Set of offsets within one `SEGMENT_LEN` window, stored in whichever form suits its density
+// (like Roaring bitmaps). Never empty inside a `HyBitSet`.
+enum Segment {
+    // Sorted offsets, for sparse segments.
+    Array(Vec<Offset>),
+    // Bits, with the number set.
+    Bitmap(Box<[u8; SEGMENT_BYTE_LEN]>, usize),
+    // Sorted, non-adjacent (start, length - 1) runs, for clustered segments.
+    // Only produced by `optimize`, converted back when modified.
+    Run(Vec<(Offset, Offset)>),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum SegmentKind {
+    Array,
+    Bitmap,
+    Run,
+}
+
+impl SegmentKind {
+    // Smallest form for a segment with `len` offsets in `runs` runs.
+    fn best(len: usize, runs: usize) -> SegmentKind {
+        let array_size = 2 * len;
+        let run_size = 4 * runs;
+        if array_size <= SEGMENT_BYTE_LEN && array_size <= run_size {
+            SegmentKind::Array
+        }
+        else if run_size < SEGMENT_BYTE_LEN {
+            SegmentKind::Run
+        }
+        else {
+            SegmentKind::Bitmap
+        }
+    }
 
-struct Segment([u8; SEGMENT_BYTE_LEN]);
+    fn tag(self) -> u8 {
+        match self {
+            SegmentKind::Array  => 0,
+            SegmentKind::Bitmap => 1,
+            SegmentKind::Run    => 2,
+        }
+    }
+}
 
 impl Segment {
     pub fn new() -> Segment {
-        Segment([0; SEGMENT_BYTE_LEN])
+        Segment::Array(vec![])
     }
 
     #[inline]
-    fn get_byte(&mut self, segment_index: usize) -> &mut u8 {
-        let byte_index = segment_index / BITS_PER_BYTE;
-        let byte_val = &mut self.0[byte_index];
-        byte_val
+    fn get_mask(segment_index: Offset) -> (usize, u8) {
+        let byte_index = (segment_index as usize) / BITS_PER_BYTE;
+        let mask = 1_u8 << ((segment_index as usize) % BITS_PER_BYTE);
+        (byte_index, mask)
     }
 
-    #[inline]
-    fn get_mask(segment_index: usize) -> u8 {
-        let mask = 1_u8 << (segment_index % BITS_PER_BYTE);
-        mask
+    fn from_bits(bits: Box<[u8; SEGMENT_BYTE_LEN]>) -> Segment {
+        let len = bits.iter().map(|byte| byte.count_ones() as usize).sum();
+        Segment::Bitmap(bits, len)
+    }
+
+    // Builds a segment of `kind` from sorted offsets.
+    fn from_sorted(kind: SegmentKind, offsets: impl Iterator<Item = Offset>) -> Segment {
+        match kind {
+            SegmentKind::Array => Segment::Array(offsets.collect()),
+            SegmentKind::Bitmap => {
+                let mut bits = Box::new([0_u8; SEGMENT_BYTE_LEN]);
+                let mut len = 0;
+                for offset in offsets {
+                    let (byte_index, mask) = Self::get_mask(offset);
+                    bits[byte_index] |= mask;
+                    len += 1;
+                }
+                Segment::Bitmap(bits, len)
+            },
+            SegmentKind::Run => Segment::Run(Self::to_runs(offsets)),
+        }
+    }
+
+    // Array or bitmap, by size.
+    fn from_offsets(offsets: Vec<Offset>) -> Segment {
+        if ARRAY_MAX_LEN < offsets.len() {
+            Self::from_sorted(SegmentKind::Bitmap, offsets.into_iter())
+        }
+        else {
+            Segment::Array(offsets)
+        }
+    }
+
+    // Combines two segments offset by offset, keeping those where `keep(in_self, in_other)`.
+    fn combine(&self, other: &Segment, keep: impl Fn(bool, bool) -> bool) -> Segment {
+        let mut a = self.iter().peekable();
+        let mut b = other.iter().peekable();
+        let mut out = vec![];
+        loop {
+            let (offset, in_a, in_b) = match (a.peek().cloned(), b.peek().cloned()) {
+                (None, None) => break,
+                (Some(x), None) => (x, true, false),
+                (None, Some(y)) => (y, false, true),
+                (Some(x), Some(y)) => (std::cmp::min(x, y), x <= y, y <= x),
+            };
+            if in_a { a.next(); }
+            if in_b { b.next(); }
+            if keep(in_a, in_b) {
+                out.push(offset);
+            }
+        }
+        Self::from_offsets(out)
+    }
+
+    // Number of offsets less than `segment_index`.
+    fn rank(&self, segment_index: Offset) -> usize {
+        match self {
+            Segment::Array(offsets) => match offsets.binary_search(&segment_index) {
+                Ok(i) | Err(i) => i,
+            },
+            Segment::Bitmap(bits, _len) => {
+                let (byte_index, mask) = Self::get_mask(segment_index);
+                let full = bits[..byte_index].iter().map(|byte| byte.count_ones() as usize).sum::<usize>();
+                full + (bits[byte_index] & (mask - 1)).count_ones() as usize
+            },
+            Segment::Run(runs) => runs.iter()
+                .take_while(|(start, _)| *start < segment_index)
+                .map(|(start, len_minus_one)| std::cmp::min(*len_minus_one as usize + 1, (segment_index - start) as usize))
+                .sum(),
+        }
+    }
+
+    fn to_runs(offsets: impl Iterator<Item = Offset>) -> Vec<(Offset, Offset)> {
+        let mut runs: Vec<(Offset, Offset)> = vec![];
+        for offset in offsets {
+            match runs.last_mut() {
+                Some((start, len_minus_one)) if (*start as usize) + (*len_minus_one as usize) + 1 == offset as usize => {
+                    *len_minus_one += 1;
+                },
+                _ => runs.push((offset, 0)),
+            }
+        }
+        runs
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Segment::Array(offsets) => offsets.len(),
+            Segment::Bitmap(_bits, len) => *len,
+            Segment::Run(runs) => runs.iter().map(|(_start, len_minus_one)| *len_minus_one as usize + 1).sum(),
+        }
+    }
+
+    // Offsets in ascending order.
+    pub fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = Offset> + 'a> {
+        match self {
+            Segment::Array(offsets) => Box::new(offsets.iter().cloned()),
+            Segment::Bitmap(bits, _len) => Box::new((0..SEGMENT_LEN)
+                .map(|i| i as Offset)
+                .filter(move |i| {
+                    let (byte_index, mask) = Self::get_mask(*i);
+                    0 != bits[byte_index] & mask
+                })),
+            Segment::Run(runs) => Box::new(runs.iter()
+                .flat_map(|(start, len_minus_one)| *start..=(*start + *len_minus_one))),
+        }
+    }
+
+    fn kind(&self) -> SegmentKind {
+        match self {
+            Segment::Array(_) => SegmentKind::Array,
+            Segment::Bitmap(_, _) => SegmentKind::Bitmap,
+            Segment::Run(_) => SegmentKind::Run,
+        }
+    }
+
+    fn best_kind(&self) -> SegmentKind {
+        let runs = match self {
+            Segment::Run(runs) => runs.len(),
+            _ => Self::to_runs(self.iter()).len(),
+        };
+        SegmentKind::best(self.len(), runs)
+    }
+
+    // Converts to the smallest form.
+    pub fn optimize(&mut self) {
+        let kind = self.best_kind();
+        if kind != self.kind() {
+            *self = Self::from_sorted(kind, self.iter());
+        }
     }
 
-    pub fn insert(&mut self, segment_index: usize) -> bool {
-        let mask = Self::get_mask(segment_index);
-        let byte_val = self.get_byte(segment_index);
+    // Run segments are expanded before modification.
+    fn expand_runs(&mut self) {
+        if let Segment::Run(_) = self {
+            let kind = if ARRAY_MAX_LEN < self.len() { SegmentKind::Bitmap } else { SegmentKind::Array };
+            *self = Self::from_sorted(kind, self.iter());
+        }
+    }
+
+    pub fn insert(&mut self, segment_index: Offset) -> bool {
+        self.expand_runs();
+        match self {
+            Segment::Array(offsets) => match offsets.binary_search(&segment_index) {
+                Ok(_) => true,
+                Err(i) => {
+                    offsets.insert(i, segment_index);
+                    if ARRAY_MAX_LEN < offsets.len() {
+                        *self = Self::from_sorted(SegmentKind::Bitmap, self.iter());
+                    }
+                    false
+                },
+            },
+            Segment::Bitmap(bits, len) => {
+                let (byte_index, mask) = Self::get_mask(segment_index);
+                let out = 0 != (mask & bits[byte_index]);
+                if !out {
+                    bits[byte_index] |= mask;
+                    *len += 1;
+                }
+                out
+            },
+            Segment::Run(_) => unreachable!(),
+        }
+    }
 
-        let out = 0 != (mask & *byte_val);
-        *byte_val |= mask;
-        out
+    pub fn remove(&mut self, segment_index: Offset) -> bool {
+        self.expand_runs();
+        match self {
+            Segment::Array(offsets) => match offsets.binary_search(&segment_index) {
+                Ok(i) => {
+                    offsets.remove(i);
+                    true
+                },
+                Err(_) => false,
+            },
+            Segment::Bitmap(bits, len) => {
+                let (byte_index, mask) = Self::get_mask(segment_index);
+                let out = 0 != (mask & bits[byte_index]);
+                if out {
+                    bits[byte_index] &= !mask;
+                    *len -= 1;
+                    if *len <= ARRAY_MAX_LEN / 2 {
+                        *self = Self::from_sorted(SegmentKind::Array, self.iter());
+                    }
+                }
+                out
+            },
+            Segment::Run(_) => unreachable!(),
+        }
     }
 
-    pub fn remove(&mut self, segment_index: usize) -> bool {
-        let mask = Self::get_mask(segment_index);
-        let byte_val = self.get_byte(segment_index);
+    pub fn contains(&self, segment_index: Offset) -> bool {
+        match self {
+            Segment::Array(offsets) => offsets.binary_search(&segment_index).is_ok(),
+            Segment::Bitmap(bits, _len) => {
+                let (byte_index, mask) = Self::get_mask(segment_index);
+                0 != (mask & bits[byte_index])
+            },
+            Segment::Run(runs) => {
+                // Last run starting at or before `segment_index`.
+                let i = match runs.binary_search_by_key(&segment_index, |(start, _)| *start) {
+                    Ok(_) => return true,
+                    Err(0) => return false,
+                    Err(i) => i - 1,
+                };
+                let (start, len_minus_one) = runs[i];
+                segment_index <= start + len_minus_one
+            },
+        }
+    }
 
-        let out = 0 != (mask & *byte_val);
-        *byte_val &= !mask;
-        out
+    // Bitmap bytes, for the JSON format.
+    fn to_bits(&self) -> Box<[u8; SEGMENT_BYTE_LEN]> {
+        match self {
+            Segment::Bitmap(bits, _len) => bits.clone(),
+            _ => match Self::from_sorted(SegmentKind::Bitmap, self.iter()) {
+                Segment::Bitmap(bits, _len) => bits,
+                _ => unreachable!(),
+            },
+        }
     }
 
-    pub fn contains(&mut self, segment_index: usize) -> bool {
-        let mask = Self::get_mask(segment_index);
-        let byte_val = self.get_byte(segment_index);
+    // Appends the binary encoding (in the smallest form) to `out`:
+    // tag, then for array and run a u16 count and the u16 values, for bitmap the bytes.
+    fn encode(&self, out: &mut Vec<u8>) {
+        let kind = self.best_kind();
+        out.push(kind.tag());
+        match kind {
+            SegmentKind::Array => {
+                out.extend_from_slice(&(self.len() as u16).to_le_bytes());
+                for offset in self.iter() {
+                    out.extend_from_slice(&offset.to_le_bytes());
+                }
+            },
+            SegmentKind::Bitmap => out.extend_from_slice(&*self.to_bits()),
+            SegmentKind::Run => {
+                let runs = match self {
+                    Segment::Run(runs) => runs.clone(),
+                    _ => Self::to_runs(self.iter()),
+                };
+                out.extend_from_slice(&(runs.len() as u16).to_le_bytes());
+                for (start, len_minus_one) in runs {
+                    out.extend_from_slice(&start.to_le_bytes());
+                    out.extend_from_slice(&len_minus_one.to_le_bytes());
+                }
+            },
+        }
+    }
 
-        let out = 0 != (mask & *byte_val);
-        out
+    // Reads one segment written by `encode` from the start of `bytes`, returns it and the bytes used.
+    // None if invalid, including empty segments and unsorted or duplicate offsets or runs.
+    fn decode(bytes: &[u8]) -> Option<(Segment, usize)> {
+        fn u16_at(bytes: &[u8], i: usize) -> Option<u16> {
+            let slice = bytes.get(i..(i + 2))?;
+            Some(u16::from_le_bytes([ slice[0], slice[1] ]))
+        }
+        let tag = *bytes.get(0)?;
+        if SegmentKind::Bitmap.tag() == tag {
+            let mut bits = Box::new([0_u8; SEGMENT_BYTE_LEN]);
+            bits.copy_from_slice(bytes.get(1..(1 + SEGMENT_BYTE_LEN))?);
+            let segment = Self::from_bits(bits);
+            if 0 == segment.len() {
+                return None;
+            }
+            return Some((segment, 1 + SEGMENT_BYTE_LEN));
+        }
+        let count = u16_at(bytes, 1)? as usize;
+        if 0 == count {
+            return None;
+        }
+        if SegmentKind::Array.tag() == tag {
+            let offsets = (0..count)
+                .map(|i| u16_at(bytes, 3 + 2 * i).filter(|offset| (*offset as usize) < SEGMENT_LEN))
+                .collect::<Option<Vec<_>>>()?;
+            // Strictly ascending, so sorted and unique.
+            if !offsets.windows(2).all(|pair| pair[0] < pair[1]) {
+                return None;
+            }
+            Some((Segment::Array(offsets), 3 + 2 * count))
+        }
+        else if SegmentKind::Run.tag() == tag {
+            let runs = (0..count)
+                .map(|i| Some((u16_at(bytes, 3 + 4 * i)?, u16_at(bytes, 5 + 4 * i)?)))
+                .map(|run| run.filter(|(start, len)| (*start as usize) + (*len as usize) < SEGMENT_LEN))
+                .collect::<Option<Vec<_>>>()?;
+            // Sorted, with a gap between runs.
+            if !runs.windows(2).all(|pair| (pair[0].0 as usize) + (pair[0].1 as usize) + 1 < pair[1].0 as usize) {
+                return None;
+            }
+            Some((Segment::Run(runs), 3 + 4 * count))
+        }
+        else {
+            None
+        }
     }
 }
 
@@ -58,7 +378,7 @@ impl Serialize for Segment {
     where
         S: Serializer,
     {
-        base64::encode(&self.0 as &[u8]).serialize(serializer)
+        base64::encode(&*self.to_bits() as &[u8]).serialize(serializer)
     }
 }
 
@@ -67,18 +387,31 @@ impl<'de> Deserialize<'de> for Segment {
     where
         D: Deserializer<'de>,
     {
-        let mut arr: [u8; SEGMENT_BYTE_LEN] = [0; SEGMENT_BYTE_LEN];
-        base64::decode_config_slice(<&str>::deserialize(deserializer)?, base64::STANDARD, &mut arr as &mut [u8])
+        let mut arr = Box::new([0_u8; SEGMENT_BYTE_LEN]);
+        base64::decode_config_slice(<&str>::deserialize(deserializer)?, base64::STANDARD, &mut *arr as &mut [u8])
             .map_err(serde::de::Error::custom)?;
-        Ok(Segment(arr))
+        let mut segment = Segment::from_bits(arr);
+        segment.optimize();
+        Ok(segment)
     }
 }
 
+// Binary format, all integers little-endian:
+// - `BINARY_MAGIC`, version: u8, compressed: u8 (0 or 1).
+// - segment_byte_len: u32, len: u64, segment count: u64.
+// - Segment ids: u64 each, ascending.
+// - Segment data in id order, deflate compressed if `compressed`.
+//   Version 1: `segment_byte_len` bitmap bytes per segment.
+//   Version 2: each segment as written by `Segment::encode`.
+const BINARY_MAGIC: &'static [u8; 4] = b"HBS\0";
+const BINARY_VERSION: u8 = 2;
+const BINARY_HEADER_LEN: usize = 4 + 1 + 1 + 4 + 8 + 8;
+
 #[derive(Serialize, Deserialize)]
 pub struct HyBitSet {
     len: usize,
     segment_byte_len: usize,
-    segment_map: HashMap<usize, Segment>,
+    segment_map: BTreeMap<usize, Segment>,
 }
 
 impl HyBitSet {
@@ -87,21 +420,21 @@ impl HyBitSet {
         HyBitSet {
             len: 0,
             segment_byte_len: SEGMENT_BYTE_LEN,
-            segment_map: HashMap::new(),
+            segment_map: BTreeMap::new(),
         }
     }
 
+    // Start of the `SEGMENT_LEN` window containing `val`.
     #[inline]
-    fn get_seg(&mut self, val: usize) -> &mut Segment {
-        let seg_id  = val - (val % SEGMENT_LEN);
-        let segment = self.segment_map.entry(seg_id).or_insert(Segment::new());
-        segment
+    pub fn get_seg_id(val: usize) -> usize {
+        let seg_id = val - (val % SEGMENT_LEN);
+        seg_id
     }
 
     #[inline]
-    fn get_off(val: usize) -> usize {
+    fn get_off(val: usize) -> Offset {
         let seg_off = val % SEGMENT_LEN;
-        seg_off
+        seg_off as Offset
     }
 
     #[allow(dead_code)]
@@ -116,7 +449,7 @@ impl HyBitSet {
     #[allow(dead_code)]
     pub fn insert(&mut self, val: usize) -> bool {
         let off = Self::get_off(val);
-        let seg = self.get_seg(val);
+        let seg = self.segment_map.entry(Self::get_seg_id(val)).or_insert_with(Segment::new);
         if seg.insert(off) {
             true
         }
@@ -129,8 +462,15 @@ impl HyBitSet {
     #[allow(dead_code)]
     pub fn remove(&mut self, val: usize) -> bool {
         let off = Self::get_off(val);
-        let seg = self.get_seg(val);
+        let seg_id = Self::get_seg_id(val);
+        let seg = match self.segment_map.get_mut(&seg_id) {
+            Some(seg) => seg,
+            None => return false,
+        };
         if seg.remove(off) {
+            if 0 == seg.len() {
+                self.segment_map.remove(&seg_id);
+            }
             self.len -= 1;
             true
         }
@@ -140,15 +480,272 @@ impl HyBitSet {
     }
 
     #[allow(dead_code)]
-    pub fn contains(&mut self, val: usize) -> bool {
+    pub fn contains(&self, val: usize) -> bool {
         let off = Self::get_off(val);
 
-        let seg_id  = val - (val % SEGMENT_LEN);
-        match self.segment_map.get_mut(&seg_id) {
+        match self.segment_map.get(&Self::get_seg_id(val)) {
             Some(seg) => seg.contains(off),
             None => false,
         }
     }
+
+    // Values in ascending order.
+    #[allow(dead_code)]
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = usize> + 'a {
+        self.segment_map.iter()
+            .flat_map(|(seg_id, seg)| seg.iter().map(move |off| seg_id + off as usize))
+    }
+
+    // Applies `keep` to the segments of `self` and `other`, for set operations.
+    // Segments only in `self` are untouched, `other_only` decides whether those only in `other` are added.
+    fn combine_with(&mut self, other: &HyBitSet, other_only: bool, keep: impl Fn(bool, bool) -> bool) {
+        for (seg_id, other_seg) in other.segment_map.iter() {
+            let combined = match self.segment_map.get(seg_id) {
+                Some(seg) => seg.combine(other_seg, &keep),
+                None if other_only => other_seg.combine(&Segment::new(), |in_other, _| in_other),
+                None => continue,
+            };
+            if 0 == combined.len() {
+                self.segment_map.remove(seg_id);
+            }
+            else {
+                self.segment_map.insert(*seg_id, combined);
+            }
+        }
+        self.len = self.segment_map.values().map(Segment::len).sum();
+    }
+
+    // Adds all values of `other`.
+    #[allow(dead_code)]
+    pub fn union_with(&mut self, other: &HyBitSet) {
+        self.combine_with(other, true, |in_self, in_other| in_self || in_other);
+    }
+
+    // Keeps only values also in `other`.
+    #[allow(dead_code)]
+    pub fn intersect_with(&mut self, other: &HyBitSet) {
+        let seg_ids = self.segment_map.keys()
+            .filter(|seg_id| !other.segment_map.contains_key(seg_id))
+            .cloned()
+            .collect::<Vec<_>>();
+        for seg_id in seg_ids {
+            self.segment_map.remove(&seg_id);
+        }
+        self.combine_with(other, false, |in_self, in_other| in_self && in_other);
+    }
+
+    // Removes all values of `other`.
+    #[allow(dead_code)]
+    pub fn difference_with(&mut self, other: &HyBitSet) {
+        self.combine_with(other, false, |in_self, in_other| in_self && !in_other);
+    }
+
+    // Number of values less than `val`.
+    #[allow(dead_code)]
+    pub fn rank(&self, val: usize) -> usize {
+        let seg_id = Self::get_seg_id(val);
+        let before = self.segment_map.range(..seg_id)
+            .map(|(_seg_id, seg)| seg.len())
+            .sum::<usize>();
+        let within = self.segment_map.get(&seg_id)
+            .map(|seg| seg.rank(Self::get_off(val)))
+            .unwrap_or(0);
+        before + within
+    }
+
+    // The `n`th smallest value (from 0), so `select(rank(val)) == Some(val)` if `val` is in the set.
+    #[allow(dead_code)]
+    pub fn select(&self, mut n: usize) -> Option<usize> {
+        for (seg_id, seg) in self.segment_map.iter() {
+            let seg_len = seg.len();
+            if n < seg_len {
+                return seg.iter().nth(n).map(|off| seg_id + off as usize);
+            }
+            n -= seg_len;
+        }
+        None
+    }
+
+    // Removes all values in `range`, returns the number removed.
+    #[allow(dead_code)]
+    pub fn remove_range(&mut self, range: impl RangeBounds<usize>) -> usize {
+        let start = match range.start_bound() {
+            Bound::Included(start) => *start,
+            Bound::Excluded(start) => match start.checked_add(1) {
+                Some(start) => start,
+                None => return 0,
+            },
+            Bound::Unbounded => 0,
+        };
+        // Exclusive, `None` for no end.
+        let end = match range.end_bound() {
+            Bound::Included(end) => end.checked_add(1),
+            Bound::Excluded(end) => Some(*end),
+            Bound::Unbounded => None,
+        };
+        let in_range = |val: usize| start <= val && end.map(|end| val < end).unwrap_or(true);
+
+        let seg_ids = self.segment_map.range(Self::get_seg_id(start)..)
+            .map(|(seg_id, _seg)| *seg_id)
+            .take_while(|seg_id| end.map(|end| *seg_id < end).unwrap_or(true))
+            .collect::<Vec<_>>();
+
+        let mut removed = 0;
+        for seg_id in seg_ids {
+            let seg = self.segment_map.remove(&seg_id).unwrap();
+            let seg_len = seg.len();
+            // Partially covered segments are filtered.
+            if !(in_range(seg_id) && in_range(seg_id + SEGMENT_LEN - 1)) {
+                let kept = seg.iter()
+                    .filter(|off| !in_range(seg_id + *off as usize))
+                    .collect::<Vec<_>>();
+                if !kept.is_empty() {
+                    removed += seg_len - kept.len();
+                    self.segment_map.insert(seg_id, Segment::from_offsets(kept));
+                    continue;
+                }
+            }
+            removed += seg_len;
+        }
+        self.len -= removed;
+        removed
+    }
+
+    // Converts every segment to its smallest form. Inserts only produce arrays and bitmaps.
+    pub fn optimize(&mut self) {
+        for seg in self.segment_map.values_mut() {
+            seg.optimize();
+        }
+    }
+
+    // Approximate heap bytes used by segments.
+    pub fn heap_size(&self) -> usize {
+        self.segment_map.values()
+            .map(|seg| match seg {
+                Segment::Array(offsets) => 2 * offsets.capacity(),
+                Segment::Bitmap(_bits, _len) => SEGMENT_BYTE_LEN,
+                Segment::Run(runs) => 4 * runs.capacity(),
+            })
+            .sum()
+    }
+
+    // Encodes in the binary format, optionally compressing the segment data.
+    pub fn to_bytes(&self, compress: bool) -> Vec<u8> {
+        let seg_ids = self.segment_map.keys().cloned().collect::<Vec<_>>();
+
+        let mut out = Vec::with_capacity(BINARY_HEADER_LEN + seg_ids.len() * 8);
+        out.extend_from_slice(BINARY_MAGIC);
+        out.push(BINARY_VERSION);
+        out.push(compress as u8);
+        out.extend_from_slice(&(self.segment_byte_len as u32).to_le_bytes());
+        out.extend_from_slice(&(self.len as u64).to_le_bytes());
+        out.extend_from_slice(&(seg_ids.len() as u64).to_le_bytes());
+        for seg_id in seg_ids.iter() {
+            out.extend_from_slice(&(*seg_id as u64).to_le_bytes());
+        }
+
+        let mut segment_bytes = vec![];
+        for seg_id in seg_ids.iter() {
+            self.segment_map[seg_id].encode(&mut segment_bytes);
+        }
+        if compress {
+            let mut encoder = DeflateEncoder::new(out, Compression::default());
+            encoder.write_all(&segment_bytes).expect("Write to vec failed.");
+            encoder.finish().expect("Write to vec failed.")
+        }
+        else {
+            out.extend_from_slice(&segment_bytes);
+            out
+        }
+    }
+
+    // Decodes the binary format written by `to_bytes` (or by version 1).
+    pub fn from_bytes(bytes: &[u8]) -> Result<HyBitSet, PbwError> {
+        fn err(msg: &str) -> PbwError {
+            PbwError::new(format!("Invalid binary HyBitSet: {}.", msg))
+        }
+        fn u64_at(bytes: &[u8], i: usize) -> u64 {
+            let mut buf = [0_u8; 8];
+            buf.copy_from_slice(&bytes[i..(i + 8)]);
+            u64::from_le_bytes(buf)
+        }
+
+        if bytes.len() < BINARY_HEADER_LEN || &bytes[0..4] != BINARY_MAGIC {
+            return Err(err("bad header"));
+        }
+        let version = bytes[4];
+        if 1 != version && BINARY_VERSION != version {
+            return Err(err(&format!("unknown version {}", version)));
+        }
+        let compressed = 0 != bytes[5];
+        let mut buf = [0_u8; 4];
+        buf.copy_from_slice(&bytes[6..10]);
+        let segment_byte_len = u32::from_le_bytes(buf) as usize;
+        if SEGMENT_BYTE_LEN != segment_byte_len {
+            return Err(err(&format!("segment_byte_len {}, expected {}", segment_byte_len, SEGMENT_BYTE_LEN)));
+        }
+        let len = u64_at(bytes, 10) as usize;
+        let count = u64_at(bytes, 18) as usize;
+
+        // `count` is unchecked, so must not overflow.
+        let ids_end = count.checked_mul(8)
+            .and_then(|ids_len| ids_len.checked_add(BINARY_HEADER_LEN))
+            .filter(|ids_end| *ids_end <= bytes.len())
+            .ok_or_else(|| err("truncated segment ids"))?;
+        let seg_ids = (0..count).map(|i| u64_at(bytes, BINARY_HEADER_LEN + i * 8) as usize);
+
+        let mut segment_bytes = bytes[ids_end..].to_vec();
+        if compressed {
+            let mut decoded = vec![];
+            DeflateDecoder::new(&*segment_bytes).read_to_end(&mut decoded)
+                .map_err(|e| err(&e.to_string()))?;
+            segment_bytes = decoded;
+        }
+
+        let mut segment_map = BTreeMap::new();
+        let mut i = 0;
+        let mut prev_seg_id = None;
+        for seg_id in seg_ids {
+            if 0 != seg_id % SEGMENT_LEN || prev_seg_id.map(|prev| prev >= seg_id).unwrap_or(false) {
+                return Err(err("segment ids not ascending"));
+            }
+            prev_seg_id = Some(seg_id);
+            let segment = if 1 == version {
+                let chunk = segment_bytes.get(i..(i + SEGMENT_BYTE_LEN))
+                    .ok_or_else(|| err("truncated segment data"))?;
+                let mut bits = Box::new([0_u8; SEGMENT_BYTE_LEN]);
+                bits.copy_from_slice(chunk);
+                i += SEGMENT_BYTE_LEN;
+                let mut segment = Segment::from_bits(bits);
+                segment.optimize();
+                segment
+            }
+            else {
+                let (segment, used) = Segment::decode(&segment_bytes[i..])
+                    .ok_or_else(|| err("bad segment data"))?;
+                i += used;
+                segment
+            };
+            // Version 1 may have kept emptied segments.
+            if 0 != segment.len() {
+                segment_map.insert(seg_id, segment);
+            }
+        }
+        if i != segment_bytes.len() {
+            return Err(err("trailing segment data"));
+        }
+        // Recomputed, since `remove` relies on it.
+        let segments_len: usize = segment_map.values().map(Segment::len).sum();
+        if len != segments_len {
+            return Err(err(&format!("len {}, but segments hold {}", len, segments_len)));
+        }
+
+        Ok(HyBitSet {
+            len: len,
+            segment_byte_len: segment_byte_len,
+            segment_map: segment_map,
+        })
+    }
 }
 
 #[cfg(test)]
@@ -211,4 +808,173 @@ mod test {
 
         assert!(true  == bs.contains(5_usize));
     }
+
+    #[test]
+    fn test_binary_roundtrip() {
+        let mut bs = HyBitSet::new();
+        for val in &[ 5_usize, 8191, 8192, 3_617_178_774, 3_651_972_316 ] {
+            bs.insert(*val);
+        }
+
+        for compress in &[ false, true ] {
+            let bytes = bs.to_bytes(*compress);
+            let bs2 = HyBitSet::from_bytes(&bytes).unwrap();
+            assert_eq!(bs.len(), bs2.len());
+            for val in &[ 5_usize, 8191, 8192, 3_617_178_774, 3_651_972_316 ] {
+                assert!(bs2.contains(*val));
+            }
+            assert!(!bs2.contains(6));
+        }
+
+        // JSON still readable.
+        let json = serde_json::to_vec(&bs).unwrap();
+        let bs3: HyBitSet = serde_json::from_slice(&json).unwrap();
+        assert!(bs3.contains(8192));
+
+        assert!(HyBitSet::from_bytes(&json).is_err());
+        assert!(HyBitSet::from_bytes(&bs.to_bytes(false)[..30]).is_err());
+
+        // Corrupt segment count, would overflow.
+        let mut bytes = bs.to_bytes(false);
+        bytes[18..26].copy_from_slice(&u64::max_value().to_le_bytes());
+        assert!(HyBitSet::from_bytes(&bytes).is_err());
+
+        // Corrupt len.
+        let mut bytes = bs.to_bytes(false);
+        bytes[10..18].copy_from_slice(&(bs.len() as u64 + 1).to_le_bytes());
+        assert!(HyBitSet::from_bytes(&bytes).is_err());
+
+        // Unsorted or duplicate array offsets, in a single array segment `[ 1, 3 ]`.
+        let mut bs = HyBitSet::new();
+        bs.insert(1);
+        bs.insert(3);
+        let bytes = bs.to_bytes(false);
+        let offsets = BINARY_HEADER_LEN + 8 + 3;
+        assert_eq!(&[ 1, 0, 3, 0 ], &bytes[offsets..]);
+        for corrupt in &[ [ 3, 0, 1, 0 ], [ 1, 0, 1, 0 ] ] {
+            let mut bytes = bytes.clone();
+            bytes[offsets..].copy_from_slice(corrupt);
+            assert!(HyBitSet::from_bytes(&bytes).is_err());
+        }
+    }
+
+    #[test]
+    fn test_containers() {
+        let mut bs = HyBitSet::new();
+        // Sparse: array.
+        for i in 0..100 {
+            bs.insert(i * 50);
+        }
+        // Dense: bitmap once past the array limit.
+        for i in 0..2000 {
+            bs.insert(SEGMENT_LEN + i * 3);
+        }
+        // Clustered: run after optimize.
+        for i in 0..4000 {
+            bs.insert(2 * SEGMENT_LEN + i);
+        }
+        assert_eq!(6100, bs.len());
+        assert_eq!(SegmentKind::Array, bs.segment_map[&0].kind());
+        assert_eq!(SegmentKind::Bitmap, bs.segment_map[&SEGMENT_LEN].kind());
+
+        bs.optimize();
+        assert_eq!(SegmentKind::Run, bs.segment_map[&(2 * SEGMENT_LEN)].kind());
+        assert!(bs.contains(2 * SEGMENT_LEN + 3999));
+        assert!(!bs.contains(2 * SEGMENT_LEN + 4000));
+        assert!(bs.contains(SEGMENT_LEN + 3));
+        assert!(!bs.contains(SEGMENT_LEN + 4));
+
+        // Modifying a run segment.
+        assert!(false == bs.insert(2 * SEGMENT_LEN + 5000));
+        assert!(true  == bs.remove(2 * SEGMENT_LEN + 10));
+        assert!(false == bs.contains(2 * SEGMENT_LEN + 10));
+        assert!(true  == bs.contains(2 * SEGMENT_LEN + 5000));
+        assert_eq!(6100, bs.len());
+
+        // Removing down to empty drops the segment.
+        for i in 0..100 {
+            assert!(bs.remove(i * 50));
+        }
+        assert!(!bs.segment_map.contains_key(&0));
+
+        let bs2 = HyBitSet::from_bytes(&bs.to_bytes(true)).unwrap();
+        assert_eq!(bs.len(), bs2.len());
+        for val in (0..(3 * SEGMENT_LEN)).step_by(7) {
+            assert_eq!(bs.contains(val), bs2.contains(val));
+        }
+    }
+
+    #[test]
+    fn test_set_ops() {
+        let a_vals = [ 1_usize, 5, 8192, 8193, 3_617_178_774 ];
+        let b_vals = [ 5_usize, 8193, 20_000, 3_617_178_774, 3_651_972_316 ];
+        let a = a_vals.iter().fold(HyBitSet::new(), |mut bs, val| { bs.insert(*val); bs });
+        let b = b_vals.iter().fold(HyBitSet::new(), |mut bs, val| { bs.insert(*val); bs });
+
+        assert_eq!(a_vals.to_vec(), a.iter().collect::<Vec<_>>());
+
+        let mut union = HyBitSet::new();
+        union.union_with(&a);
+        union.union_with(&b);
+        assert_eq!(vec![ 1, 5, 8192, 8193, 20_000, 3_617_178_774, 3_651_972_316 ], union.iter().collect::<Vec<_>>());
+        assert_eq!(7, union.len());
+
+        let mut inter = HyBitSet::new();
+        inter.union_with(&a);
+        inter.intersect_with(&b);
+        assert_eq!(vec![ 5, 8193, 3_617_178_774 ], inter.iter().collect::<Vec<_>>());
+        assert_eq!(3, inter.len());
+
+        let mut diff = HyBitSet::new();
+        diff.union_with(&a);
+        diff.difference_with(&b);
+        assert_eq!(vec![ 1, 8192 ], diff.iter().collect::<Vec<_>>());
+        assert_eq!(2, diff.len());
+    }
+
+    #[test]
+    fn test_rank_select() {
+        let mut bs = HyBitSet::new();
+        for i in 0..3000 {
+            bs.insert(100 + i * 7);
+        }
+        bs.insert(5_000_000);
+        for i in 0..3000 {
+            assert_eq!(i, bs.rank(100 + i * 7));
+            assert_eq!(i + 1, bs.rank(101 + i * 7));
+            assert_eq!(Some(100 + i * 7), bs.select(i));
+        }
+        assert_eq!(3000, bs.rank(5_000_000));
+        assert_eq!(Some(5_000_000), bs.select(3000));
+        assert_eq!(None, bs.select(3001));
+
+        bs.optimize();
+        for i in 0..3000 {
+            assert_eq!(i, bs.rank(100 + i * 7));
+        }
+    }
+
+    #[test]
+    fn test_remove_range() {
+        let mut bs = HyBitSet::new();
+        for i in 0..(4 * SEGMENT_LEN) {
+            bs.insert(i);
+        }
+        bs.optimize();
+
+        // Partial, whole, partial segments.
+        assert_eq!(2 * SEGMENT_LEN, bs.remove_range(100..(2 * SEGMENT_LEN + 100)));
+        assert_eq!(2 * SEGMENT_LEN, bs.len());
+        assert!(bs.contains(99));
+        assert!(!bs.contains(100));
+        assert!(!bs.contains(2 * SEGMENT_LEN + 99));
+        assert!(bs.contains(2 * SEGMENT_LEN + 100));
+        assert!(!bs.segment_map.contains_key(&SEGMENT_LEN));
+
+        assert_eq!(100, bs.remove_range(..=99));
+        assert_eq!(Some(2 * SEGMENT_LEN + 100), bs.select(0));
+        assert_eq!(SEGMENT_LEN + SEGMENT_LEN - 100, bs.remove_range(3..));
+        assert_eq!(0, bs.len());
+        assert!(bs.segment_map.is_empty());
+    }
 }
@@ -1,26 +1,64 @@
-use std::convert::Into;
+use riven::consts::{ Division, Tier };
 
-use riven::consts::Tier;
-use riven::consts::IntoEnumIterator;
+// Ladder score of `MASTER` with 0 LP. Below it each tier spans 400 and each division 100, plus LP.
+// Master and above share one ladder above this, ordered by LP.
+pub const APEX_SCORE: u16 = 2400;
+// Rough LP floors of the apex tiers, used when LP is unknown and to label apex scores.
+const APEX_FLOORS: [(Tier, u16); 3] = [
+    (Tier::CHALLENGER, 500),
+    (Tier::GRANDMASTER, 200),
+    (Tier::MASTER, 0),
+];
+const DIVISIONS: [Division; 4] = [ Division::IV, Division::III, Division::II, Division::I ];
 
-pub fn match_avg_tier<I: Iterator<Item = Option<Tier>>>(tiers: I) -> Option<Tier> {
-    let (sum, cnt) = tiers.filter_map(std::convert::identity)
-        .map(|tier| Into::<u8>::into(tier) as u16)
-        .fold((0_u16, 0_u16), |(sum, cnt), x| (sum + x, cnt + 1));
+// Continuous ladder score of a rank, so ranks can be averaged and compared.
+// Unknown division or LP are assumed to be the middle of the tier or division.
+pub fn rank_score(tier: Tier, division: Option<Division>, league_points: Option<i32>) -> u16 {
+    let tier_idx = match tier {
+        Tier::IRON => 0,
+        Tier::BRONZE => 1,
+        Tier::SILVER => 2,
+        Tier::GOLD => 3,
+        Tier::PLATINUM => 4,
+        Tier::DIAMOND => 5,
+        Tier::MASTER | Tier::GRANDMASTER | Tier::CHALLENGER => {
+            let floor = APEX_FLOORS.iter().find(|(apex, _)| *apex == tier).map(|(_, floor)| *floor).unwrap_or(0);
+            let lp = league_points.map(|lp| lp.max(0) as u16).unwrap_or(floor);
+            return APEX_SCORE + lp;
+        },
+    };
+    let base = tier_idx * 400;
+    match division {
+        None => base + 200,
+        Some(division) => {
+            let div_idx = DIVISIONS.iter().position(|div| *div == division).unwrap_or(0) as u16;
+            let lp = league_points.map(|lp| lp.max(0).min(100) as u16).unwrap_or(50);
+            base + div_idx * 100 + lp
+        },
+    }
+}
+
+// Tier and division a ladder score falls in, stored as a match's `rank_tier` and `rank_division` label.
+// Apex scores have no division.
+pub fn score_rank(score: u16) -> (Tier, Option<Division>) {
+    if score >= APEX_SCORE {
+        let lp = score - APEX_SCORE;
+        let tier = APEX_FLOORS.iter().find(|(_, floor)| lp >= *floor).map(|(tier, _)| *tier).unwrap_or(Tier::MASTER);
+        return (tier, None);
+    }
+    let tier = [ Tier::IRON, Tier::BRONZE, Tier::SILVER, Tier::GOLD, Tier::PLATINUM, Tier::DIAMOND ][(score / 400) as usize];
+    (tier, Some(DIVISIONS[((score % 400) / 100) as usize]))
+}
+
+// Average ladder score of the ranked participants, rounded.
+// A bracket like "Platinum II+" is `score >= rank_score(Tier::PLATINUM, Some(Division::II), Some(0))`.
+pub fn match_avg_rank<I: Iterator<Item = Option<u16>>>(scores: I) -> Option<u16> {
+    let (sum, cnt) = scores.filter_map(std::convert::identity)
+        .fold((0_u32, 0_u32), |(sum, cnt), x| (sum + x as u32, cnt + 1));
     if 0 == cnt {
         return None;
     }
-    let tier_avg = ((sum as f32) / (cnt as f32)) as u8; 
-    let (_dist, tier_nearest) = Tier::iter().fold((u8::max_value(), Tier::CHALLENGER), |acc, tier| {
-        let dist = crate::distance(tier_avg, Into::<u8>::into(tier));
-        if dist < acc.0 {
-            (dist, tier)
-        }
-        else {
-            acc
-        }
-    });
-    Some(tier_nearest)
+    Some(((sum + cnt / 2) / cnt) as u16)
 }
 
 pub fn parse_version(version: &str) -> Option<(u8, u8)> {
@@ -37,9 +75,24 @@ mod test {
     use super::*;
 
     #[test]
-    fn test_round_up() {
-        let x = [ Some(&Tier::CHALLENGER), Some(&Tier::GRANDMASTER) ];
-        assert_eq!(Some(Tier::CHALLENGER), match_avg_tier(x.iter().cloned()));
+    fn test_rank_score() {
+        assert_eq!(1200, rank_score(Tier::GOLD, Some(Division::IV), Some(0)));
+        assert_eq!(1875, rank_score(Tier::PLATINUM, Some(Division::II), Some(75)));
+        assert_eq!(1800, rank_score(Tier::PLATINUM, None, None));
+        assert_eq!(2450, rank_score(Tier::CHALLENGER, None, Some(50)));
+        assert!(rank_score(Tier::DIAMOND, Some(Division::I), Some(99)) < rank_score(Tier::MASTER, None, Some(0)));
+
+        assert_eq!((Tier::PLATINUM, Some(Division::II)), score_rank(1875));
+        assert_eq!((Tier::IRON, Some(Division::IV)), score_rank(0));
+        assert_eq!((Tier::GRANDMASTER, None), score_rank(APEX_SCORE + 300));
+    }
+
+    #[test]
+    fn test_match_avg_rank() {
+        // Gold I 90 LP and Platinum IV 10 LP average to the Gold/Platinum border.
+        let x = [ Some(1590), None, Some(1610) ];
+        assert_eq!(Some(1600), match_avg_rank(x.iter().cloned()));
+        assert_eq!(None, match_avg_rank(vec![ None, None ].into_iter()));
     }
 
     #[test]
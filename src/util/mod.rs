@@ -1,6 +1,9 @@
+pub mod atomic_file;
 pub mod csvgz;
 pub mod error;
 pub mod file_find;
 pub mod hybitset;
 pub mod lol;
+pub mod queue;
+pub mod shutdown;
 pub mod time;
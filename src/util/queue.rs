@@ -0,0 +1,76 @@
+use std::path::{ Path, PathBuf };
+
+use riven::consts::{ Queue, QueueType, Region };
+
+// A queue to crawl. `league_queue_type` is the ladder used to find summoners and ranks,
+// queues without their own ladder (normal draft, ARAM) use the solo queue ladder.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueueConfig {
+    pub name: &'static str,
+    pub queue: Queue,
+    pub league_queue_type: QueueType,
+}
+
+pub const SOLO: QueueConfig = QueueConfig {
+    name: "solo",
+    queue: Queue::SUMMONERS_RIFT_5V5_RANKED_SOLO_GAMES,
+    league_queue_type: QueueType::RANKED_SOLO_5x5,
+};
+pub const FLEX: QueueConfig = QueueConfig {
+    name: "flex",
+    queue: Queue::SUMMONERS_RIFT_5V5_RANKED_FLEX_GAMES,
+    league_queue_type: QueueType::RANKED_FLEX_SR,
+};
+pub const NORMAL_DRAFT: QueueConfig = QueueConfig {
+    name: "normal-draft",
+    queue: Queue::SUMMONERS_RIFT_5V5_DRAFT_PICK_GAMES,
+    league_queue_type: QueueType::RANKED_SOLO_5x5,
+};
+pub const ARAM: QueueConfig = QueueConfig {
+    name: "aram",
+    queue: Queue::HOWLING_ABYSS_5V5_ARAM_GAMES,
+    league_queue_type: QueueType::RANKED_SOLO_5x5,
+};
+
+pub const ALL: [QueueConfig; 4] = [ SOLO, FLEX, NORMAL_DRAFT, ARAM ];
+
+pub fn parse(name: &str) -> Option<QueueConfig> {
+    let name = name.to_lowercase();
+    ALL.iter().find(|queue| queue.name == name).cloned()
+}
+
+impl QueueConfig {
+    // Root of this queue's data. Solo queue stays at the top level, where its files are published from,
+    // other queues get their own `<data_root>/<name>` tree.
+    pub fn queue_root(&self, data_root: impl AsRef<Path>) -> PathBuf {
+        if SOLO == *self {
+            data_root.as_ref().to_owned()
+        }
+        else {
+            data_root.as_ref().join(self.name)
+        }
+    }
+
+    pub fn path_data(&self, data_root: impl AsRef<Path>, region: Region) -> PathBuf {
+        self.queue_root(data_root).join(format!("{:?}", region).to_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Some(FLEX), parse("flex"));
+        assert_eq!(Some(ARAM), parse("ARAM"));
+        assert_eq!(None, parse("blind"));
+    }
+
+    #[test]
+    fn test_path_data() {
+        assert_eq!(PathBuf::from("data/na"), SOLO.path_data("data", Region::NA));
+        assert_eq!(PathBuf::from("data/flex/na"), FLEX.path_data("data", Region::NA));
+        assert_eq!(PathBuf::from("data/normal-draft/euw"), NORMAL_DRAFT.path_data("data", Region::EUW));
+    }
+}
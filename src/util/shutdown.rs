@@ -0,0 +1,63 @@
+use std::sync::atomic::{ AtomicBool, Ordering };
+
+// Exit status when a run stopped early because of a signal.
+pub const EXIT_INTERRUPTED: i32 = 3;
+// Exit status when a second signal forces an immediate exit.
+pub const EXIT_FORCED: i32 = 130;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// True once SIGINT/SIGTERM has been received.
+// Stages stop issuing new API requests but still finish saving what they have.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+// Marks shutdown as requested, returning true if it already was.
+pub fn request() -> bool {
+    REQUESTED.swap(true, Ordering::SeqCst)
+}
+
+// Spawns the signal handler task. Must be called inside the runtime.
+pub fn install() {
+    tokio::spawn(async {
+        let mut signals = Signals::new();
+        loop {
+            signals.recv().await;
+            if request() {
+                println!("Second signal received, exiting immediately.");
+                std::process::exit(EXIT_FORCED);
+            }
+            println!("Shutdown requested, saving in-flight work. Signal again to force quit.");
+        }
+    });
+}
+
+// SIGINT (Ctrl-C) and, on unix, SIGTERM.
+struct Signals {
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+}
+
+impl Signals {
+    fn new() -> Self {
+        Self {
+            #[cfg(unix)]
+            terminate: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("Failed to install SIGTERM handler."),
+        }
+    }
+
+    #[cfg(unix)]
+    async fn recv(&mut self) {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = self.terminate.recv() => {},
+        };
+    }
+
+    #[cfg(not(unix))]
+    async fn recv(&mut self) {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}